@@ -0,0 +1,122 @@
+//! load benchmarks for the loop
+//!
+//! Opens a large number of idle connections to a listener, then measures how many round trips
+//! per second a handful of busy connections get through while the idle ones are registered.
+//!
+//! Run with `cargo bench`.  The number of idle connections is taken from `IDLE_CONNECTIONS`
+//! (default 100000), so the open file limit has to allow twice that many descriptors; the
+//! number of busy connections from `BUSY_CONNECTIONS` (default 64), how long to measure for from
+//! `BENCH_SECONDS` (default 5), and the address to listen on from `BENCH_ADDR` (default
+//! 127.0.0.1:7878).
+
+extern crate mio;
+extern crate tcp_loop;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tcp_loop::{InputMessage, ListenOptions, Loop, OutputMessage, SequentialTokenFactory, Token};
+
+fn setting (name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|x| x.parse().ok()).unwrap_or(default)
+}
+
+fn main () {
+    let idle = setting("IDLE_CONNECTIONS", 100_000);
+    let busy = setting("BUSY_CONNECTIONS", 64);
+    let seconds = setting("BENCH_SECONDS", 5);
+
+    let (downstream, output) = tcp_loop::channel();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut eloop = Loop::new(SequentialTokenFactory::new(), downstream).unwrap();
+        tx.send(eloop.channel()).unwrap();
+        eloop.run().unwrap();
+    });
+
+    let input = rx.recv().unwrap();
+    let addr: SocketAddr = env::var("BENCH_ADDR").unwrap_or("127.0.0.1:7878".to_string()).parse().unwrap();
+    let listener = mio::Token(usize::max_value() >> 2);
+
+    // accept in big batches so the idle connections go in quickly
+    let mut options = ListenOptions::default();
+    options.accept_batch = 1024;
+
+    input.send(InputMessage::ListenRequest {
+        listener: listener,
+        addr:     addr,
+        options:  options,
+        context:  None,
+    }).unwrap();
+
+    match output.recv().unwrap() {
+        OutputMessage::ListenResponse { .. } => {},
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // idle connections
+    let start = Instant::now();
+    let mut idle_streams = Vec::with_capacity(idle);
+    for _ in 0..idle {
+        idle_streams.push(TcpStream::connect(addr).unwrap());
+    }
+    for _ in 0..idle {
+        expect_connect(&output);
+    }
+    report("accepted idle connections", idle, start.elapsed());
+
+    // busy connections echo through the downstream
+    let mut busy_streams = Vec::with_capacity(busy);
+    for _ in 0..busy {
+        busy_streams.push(TcpStream::connect(addr).unwrap());
+        expect_connect(&output);
+    }
+
+    let echo_input = input.clone();
+    thread::spawn(move || {
+        for message in output.iter() {
+            if let OutputMessage::Data { token, data, .. } = message {
+                if echo_input.send(InputMessage::Data { token: token, data: data }).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let deadline = Duration::from_secs(seconds as u64);
+    let start = Instant::now();
+    let mut round_trips = 0;
+    let mut buf = [0u8; 64];
+
+    while start.elapsed() < deadline {
+        for stream in busy_streams.iter_mut() {
+            stream.write_all(&buf).unwrap();
+        }
+        for stream in busy_streams.iter_mut() {
+            stream.read_exact(&mut buf).unwrap();
+        }
+        round_trips += busy;
+    }
+
+    report(&format!("round trips with {} idle connections", idle), round_trips, start.elapsed());
+
+    drop(idle_streams);
+    input.send(InputMessage::Shutdown).unwrap();
+}
+
+fn expect_connect (output: &mpsc::Receiver<OutputMessage>) -> Token {
+    match output.recv().unwrap() {
+        OutputMessage::ConnectRequest { client, .. } => client,
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn report (what: &str, count: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{}: {} in {:.3}s ({:.0}/s)", what, count, seconds, count as f64 / seconds);
+}
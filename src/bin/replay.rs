@@ -0,0 +1,70 @@
+//! play the peer's side of a recorded connection against a running loop
+//!
+//! usage: replay [--realtime] [--listen] <capture file> <address>
+//!
+//! By default the peer connects to the address, as the client of a connection a listener
+//! accepted would have.  With `--listen`, it listens on the address and waits for the loop to
+//! connect, as the server of an outgoing connection would have.  With `--realtime`, the peer's
+//! data is sent with its original timing.
+
+extern crate tcp_loop;
+
+use std::env;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process;
+
+use tcp_loop::capture::{self, Reader};
+
+fn usage () -> ! {
+    let _ = writeln!(io::stderr(), "usage: replay [--realtime] [--listen] <capture file> <address>");
+    process::exit(2);
+}
+
+fn main () {
+    let mut realtime = false;
+    let mut listen = false;
+    let mut positional = Vec::new();
+
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--realtime" => realtime = true,
+            "--listen"   => listen = true,
+            _ if arg.starts_with("--") => usage(),
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 2 {
+        usage();
+    }
+
+    let addr: SocketAddr = match positional[1].parse() {
+        Ok(addr) => addr,
+        Err(_) => usage(),
+    };
+
+    match run(&positional[0], addr, realtime, listen) {
+        Ok(0) => println!("replayed without differences"),
+        Ok(mismatches) => {
+            println!("{} chunks differed from the capture", mismatches);
+            process::exit(1);
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "replay failed: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+fn run (path: &str, addr: SocketAddr, realtime: bool, listen: bool) -> Result<usize, io::Error> {
+    let records = try!(Reader::open(path));
+
+    let mut stream = if listen {
+        try!(try!(TcpListener::bind(addr)).accept()).0
+    } else {
+        try!(TcpStream::connect(addr))
+    };
+
+    capture::replay(records, &mut stream, realtime)
+}
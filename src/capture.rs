@@ -0,0 +1,488 @@
+//! recording what connections read and write, and replaying it as a fake peer
+//!
+//! Connections are recorded when their ListenOptions or ConnectOptions name a capture directory.
+//! Each connection gets a file of its own there, named after its token and when it was set up.
+//! The files of every connection are written by one Recorder thread, so the loop never waits on
+//! the disk; a capture that finds the thread too far behind is given up on.
+//!
+//! A capture file starts with MAGIC, followed by a record for each chunk of data read from or
+//! written to the socket:
+//!
+//! - the time since the capture started, in microseconds (u64, big endian)
+//! - the direction (u8): 0 for data read from the peer, 1 for data written to it
+//! - the length of the data (u32, big endian)
+//! - the data
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the first bytes of every capture file
+pub const MAGIC: &'static [u8] = b"TCPLOOP\x01";
+
+/// the most records, across all captures, that may be waiting to be written
+const BACKLOG: usize = 4096;
+
+/// which way a chunk of data went
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// read from the peer
+    Read,
+
+    /// written to the peer
+    Write,
+}
+
+/// one chunk of a capture
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// how long after the capture started the chunk went through the socket
+    pub at:        Duration,
+
+    pub direction: Direction,
+    pub data:      Vec<u8>,
+}
+
+impl Record {
+    fn encode (&self) -> Vec<u8> {
+        let micros = self.at.as_secs() * 1_000_000 + self.at.subsec_nanos() as u64 / 1_000;
+        let len = self.data.len() as u32;
+
+        let mut out = Vec::with_capacity(13 + self.data.len());
+        for i in (0..8).rev() {
+            out.push((micros >> (i * 8)) as u8);
+        }
+        out.push(match self.direction {
+            Direction::Read  => 0,
+            Direction::Write => 1,
+        });
+        for i in (0..4).rev() {
+            out.push((len >> (i * 8)) as u8);
+        }
+        out.extend(self.data.iter().map(|x| *x));
+
+        out
+    }
+}
+
+/// what the writing thread is asked to do
+enum Message {
+    /// start a capture, with MAGIC already buffered
+    Open(u64, BufWriter<File>),
+
+    /// add an encoded record to a capture
+    Record(u64, Vec<u8>),
+
+    /// finish a capture
+    Close(u64),
+}
+
+/// writes the captures of every connection from a single thread
+///
+/// The thread is only started by the first capture, and finishes once the Recorder and all of its
+/// Writers are dropped.  Records from every capture share one queue of at most BACKLOG.
+pub struct Recorder {
+    messages: Option<Sender<Message>>,
+    backlog:  Arc<AtomicUsize>,
+    next_id:  u64,
+}
+
+impl Recorder {
+    pub fn new () -> Recorder {
+        Recorder {
+            messages: None,
+            backlog:  Arc::new(AtomicUsize::new(0)),
+            next_id:  0,
+        }
+    }
+
+    /// start a capture in a new file at `path`
+    pub fn create<P: AsRef<Path>> (&mut self, path: P) -> Result<Writer, io::Error> {
+        let mut file = BufWriter::new(try!(File::create(path)));
+        try!(file.write_all(MAGIC));
+
+        if self.messages.is_none() {
+            self.messages = Some(try!(self.start()));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let messages = match self.messages.as_ref().map(|messages| (messages.clone(), messages.send(Message::Open(id, file)))) {
+            Some((messages, Ok(_))) => messages,
+            _ => {
+                // the thread has gone, so start afresh next time
+                self.messages = None;
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "capture thread has stopped"));
+            },
+        };
+
+        Ok(Writer {
+            id:       id,
+            messages: messages,
+            backlog:  self.backlog.clone(),
+            start:    Instant::now(),
+        })
+    }
+
+    fn start (&self) -> Result<Sender<Message>, io::Error> {
+        let (messages, queue) = mpsc::channel();
+        let backlog = self.backlog.clone();
+
+        try!(thread::Builder::new().name("capture".to_string()).spawn(move || write_captures(queue, &*backlog)));
+
+        Ok(messages)
+    }
+}
+
+/// records the chunks of one connection, through its Recorder's thread
+///
+/// The capture is finished once the Writer is dropped.
+pub struct Writer {
+    id:       u64,
+    messages: Sender<Message>,
+    backlog:  Arc<AtomicUsize>,
+    start:    Instant,
+}
+
+impl Writer {
+    /// queue a chunk to be recorded
+    ///
+    /// Fails rather than wait if the writing thread has fallen behind, or if it has stopped.
+    pub fn record (&mut self, direction: Direction, data: &[u8]) -> Result<(), io::Error> {
+        if self.backlog.fetch_add(1, Ordering::SeqCst) >= BACKLOG {
+            self.backlog.fetch_sub(1, Ordering::SeqCst);
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "capture has fallen behind"));
+        }
+
+        let record = Record {
+            at:        self.start.elapsed(),
+            direction: direction,
+            data:      data.to_vec(),
+        };
+
+        match self.messages.send(Message::Record(self.id, record.encode())) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.backlog.fetch_sub(1, Ordering::SeqCst);
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "capture thread has stopped"))
+            },
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop (&mut self) {
+        let _ = self.messages.send(Message::Close(self.id));
+    }
+}
+
+/// write out captures until every sender is dropped, flushing each time the queue runs dry
+///
+/// A capture that fails to write is closed, and whatever else is queued for it is dropped.
+fn write_captures (queue: Receiver<Message>, backlog: &AtomicUsize) {
+    let mut files = HashMap::new();
+    let mut dirty = HashSet::new();
+
+    while let Ok(message) = queue.recv() {
+        let mut next = Some(message);
+
+        while let Some(message) = next {
+            match message {
+                Message::Open(id, file) => {
+                    files.insert(id, file);
+                },
+
+                Message::Record(id, record) => {
+                    backlog.fetch_sub(1, Ordering::SeqCst);
+
+                    let result = match files.get_mut(&id) {
+                        Some(file) => file.write_all(&record),
+                        None => Ok(()),
+                    };
+
+                    match result {
+                        Ok(_) => {
+                            dirty.insert(id);
+                        },
+                        Err(e) => {
+                            error!("failed to write capture {}: {:?}", id, e);
+                            files.remove(&id);
+                        },
+                    }
+                },
+
+                Message::Close(id) => {
+                    if let Some(mut file) = files.remove(&id) {
+                        if let Err(e) = file.flush() {
+                            error!("failed to write capture {}: {:?}", id, e);
+                        }
+                    }
+                    dirty.remove(&id);
+                },
+            }
+
+            next = queue.try_recv().ok();
+        }
+
+        for id in dirty.drain() {
+            let failed = match files.get_mut(&id).map(|file| file.flush()) {
+                Some(Err(e)) => {
+                    error!("failed to write capture {}: {:?}", id, e);
+                    true
+                },
+                _ => false,
+            };
+
+            if failed {
+                files.remove(&id);
+            }
+        }
+    }
+}
+
+/// reads the records of a capture file in order
+pub struct Reader<R: Read> {
+    input: R,
+}
+
+impl Reader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>> (path: P) -> Result<Reader<BufReader<File>>, io::Error> {
+        Reader::new(BufReader::new(try!(File::open(path))))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new (mut input: R) -> Result<Reader<R>, io::Error> {
+        let mut magic = [0; 8];
+        try!(input.read_exact(&mut magic));
+        if &magic[..] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a capture file"));
+        }
+
+        Ok(Reader {
+            input: input,
+        })
+    }
+
+    fn read_record (&mut self) -> Result<Option<Record>, io::Error> {
+        let mut header = [0; 13];
+
+        // the file may only end between records
+        let mut filled = 0;
+        while filled < header.len() {
+            match try!(self.input.read(&mut header[filled..])) {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record")),
+                read => filled += read,
+            }
+        }
+
+        let micros = header[..8].iter().fold(0u64, |acc, &x| (acc << 8) | x as u64);
+        let direction = match header[8] {
+            0 => Direction::Read,
+            1 => Direction::Write,
+            x => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown direction {}", x))),
+        };
+        let len = header[9..].iter().fold(0u32, |acc, &x| (acc << 8) | x as u32);
+
+        let mut data = vec![0; len as usize];
+        try!(self.input.read_exact(&mut data));
+
+        Ok(Some(Record {
+            at:        Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000),
+            direction: direction,
+            data:      data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record, io::Error>;
+
+    fn next (&mut self) -> Option<Result<Record, io::Error>> {
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// play the peer's side of a capture over `stream`
+///
+/// What the peer sent is written to `stream`, at the same times it originally arrived if
+/// `realtime` is set, or as fast as possible otherwise.  What the loop wrote is read back from
+/// `stream` and compared with the capture.  Returns how many chunks came back different.
+pub fn replay<R: Read> (records: Reader<R>, stream: &mut TcpStream, realtime: bool) -> Result<usize, io::Error> {
+    let start = Instant::now();
+    let mut mismatches = 0;
+
+    for record in records {
+        let record = try!(record);
+
+        match record.direction {
+            Direction::Read => {
+                if realtime {
+                    let elapsed = start.elapsed();
+                    if record.at > elapsed {
+                        thread::sleep(record.at - elapsed);
+                    }
+                }
+
+                try!(stream.write_all(&record.data));
+            },
+
+            Direction::Write => {
+                let mut received = vec![0; record.data.len()];
+                try!(stream.read_exact(&mut received));
+
+                if received != record.data {
+                    warn!("expected {:?} at {:?}, received {:?}", record.data, record.at, received);
+                    mismatches += 1;
+                }
+            },
+        }
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{replay, Direction, Reader, Record, Recorder, MAGIC};
+
+    fn record (micros: u64, direction: Direction, data: &[u8]) -> Record {
+        Record {
+            at:        Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000),
+            direction: direction,
+            data:      data.to_vec(),
+        }
+    }
+
+    fn capture (records: &[Record]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        for record in records {
+            out.extend(record.encode());
+        }
+        out
+    }
+
+    #[test]
+    fn round_trip () {
+        let records = vec![
+            record(0, Direction::Read, b"hello"),
+            record(1_500_001, Direction::Write, b""),
+            record(u32::max_value() as u64 * 1_000, Direction::Write, &[0xff; 300]),
+        ];
+
+        let read: Vec<Record> = Reader::new(Cursor::new(capture(&records))).unwrap().map(|x| x.unwrap()).collect();
+
+        assert_eq!(read.len(), records.len());
+        for (read, record) in read.iter().zip(records.iter()) {
+            assert_eq!(read.at, record.at);
+            assert_eq!(read.direction, record.direction);
+            assert_eq!(read.data, record.data);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_captures () {
+        assert!(Reader::new(Cursor::new(b"TCPLOOP\x02".to_vec())).is_err());
+
+        // a record cut short, in its header and in its data
+        let whole = capture(&[record(0, Direction::Read, b"hello")]);
+        for &end in [MAGIC.len() + 4, whole.len() - 1].iter() {
+            let mut reader = Reader::new(Cursor::new(whole[..end].to_vec())).unwrap();
+            assert!(reader.next().unwrap().is_err());
+        }
+
+        let mut bad = whole.clone();
+        bad[MAGIC.len() + 8] = 2;
+        assert!(Reader::new(Cursor::new(bad)).unwrap().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn shares_one_thread () {
+        let directory = env::temp_dir().join(format!("capture-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let paths = [directory.join("0.cap"), directory.join("1.cap")];
+
+        let mut recorder = Recorder::new();
+        let mut first = recorder.create(&paths[0]).unwrap();
+        let mut second = recorder.create(&paths[1]).unwrap();
+
+        first.record(Direction::Read, b"one").unwrap();
+        second.record(Direction::Write, b"two").unwrap();
+        first.record(Direction::Write, b"three").unwrap();
+        drop(first);
+        drop(second);
+
+        // the files are finished by the thread, some time after the writers are dropped
+        let expected: [&[&[u8]]; 2] = [&[b"one", b"three"], &[b"two"]];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for (path, expected) in paths.iter().zip(expected.iter()) {
+            loop {
+                // nothing, not even MAGIC, may have been written yet
+                let read: Vec<Vec<u8>> = match Reader::open(path) {
+                    Ok(reader) => reader.filter_map(|x| x.ok()).map(|x| x.data).collect(),
+                    Err(_) => Vec::new(),
+                };
+                if read.len() == expected.len() {
+                    assert!(read.iter().zip(expected.iter()).all(|(read, expected)| &read[..] == *expected));
+                    break;
+                }
+
+                assert!(Instant::now() < deadline, "capture {:?} was not written", path);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// play the loop's side of a connection: expect `read`, then answer with `write`
+    fn fake_loop (read: &'static [u8], write: &'static [u8]) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = vec![0; read.len()];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], read);
+            stream.write_all(write).unwrap();
+        });
+
+        (stream, thread)
+    }
+
+    #[test]
+    fn replays_the_peer () {
+        let records = capture(&[record(0, Direction::Read, b"ping"), record(10, Direction::Write, b"pong")]);
+
+        let (mut stream, thread) = fake_loop(b"ping", b"pong");
+        assert_eq!(replay(Reader::new(Cursor::new(records.clone())).unwrap(), &mut stream, false).unwrap(), 0);
+        thread.join().unwrap();
+
+        // a different answer is counted
+        let (mut stream, thread) = fake_loop(b"ping", b"pang");
+        assert_eq!(replay(Reader::new(Cursor::new(records)).unwrap(), &mut stream, false).unwrap(), 1);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// an address range in CIDR notation, like `10.0.0.0/8` or `fe80::/10`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr:   IpAddr,
+    prefix: u8,
+}
+
+/// the error produced when a string can't be parsed as a Cidr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseCidrError;
+
+fn max_prefix (addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+// IPv4 peers accepted on an IPv6 socket show up as v4-mapped addresses
+fn unmap (addr: &IpAddr) -> IpAddr {
+    match *addr {
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            Some(v4) if v6.segments()[5] == 0xffff => IpAddr::V4(v4),
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+impl Cidr {
+    /// create a range from a base address and prefix length
+    ///
+    /// Returns None if the prefix is longer than the address.  A range inside the v4-mapped
+    /// block, like `::ffff:10.0.0.0/104`, is stored as the IPv4 range it maps, since that's how
+    /// peers in it are compared.
+    pub fn new (addr: IpAddr, prefix: u8) -> Option<Cidr> {
+        if prefix > max_prefix(&addr) {
+            return None;
+        }
+
+        let (addr, prefix) = match unmap(&addr) {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => (IpAddr::V4(v4), prefix - 96),
+            _ => (addr, prefix),
+        };
+
+        Some(Cidr {
+            addr:   addr,
+            prefix: prefix,
+        })
+    }
+
+    /// whether an address falls inside this range
+    pub fn contains (&self, addr: &IpAddr) -> bool {
+        match (self.addr, unmap(addr)) {
+            (IpAddr::V4(base), IpAddr::V4(addr)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix) };
+                u32::from(base) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(base), IpAddr::V6(addr)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u128 << (128 - self.prefix) };
+                u128::from(base) & mask == u128::from(addr) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str (s: &str) -> Result<Cidr, ParseCidrError> {
+        let mut parts = s.splitn(2, '/');
+
+        let addr: IpAddr = match parts.next().map(|x| x.parse()) {
+            Some(Ok(addr)) => addr,
+            _ => return Err(ParseCidrError),
+        };
+
+        let prefix = match parts.next() {
+            None => max_prefix(&addr),
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) => prefix,
+                Err(_) => return Err(ParseCidrError),
+            },
+        };
+
+        Cidr::new(addr, prefix).ok_or(ParseCidrError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Cidr, ParseCidrError};
+
+    fn ip (s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_v4 () {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(!cidr.contains(&ip("11.0.0.0")));
+
+        // a bare address is a range of one
+        let single: Cidr = "192.168.0.1".parse().unwrap();
+        assert!(single.contains(&ip("192.168.0.1")));
+        assert!(!single.contains(&ip("192.168.0.2")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("203.0.113.9")));
+        assert!(!all.contains(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn parses_v6 () {
+        let cidr: Cidr = "fe80::/10".parse().unwrap();
+        assert!(cidr.contains(&ip("fe80::1")));
+        assert!(cidr.contains(&ip("febf::1")));
+        assert!(!cidr.contains(&ip("fec0::1")));
+        assert!(!cidr.contains(&ip("10.0.0.1")));
+
+        let single: Cidr = "2001:db8::1".parse().unwrap();
+        assert!(single.contains(&ip("2001:db8::1")));
+        assert!(!single.contains(&ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn matches_mapped_addresses () {
+        // v4 peers on a v6 socket match v4 rules
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(&ip("::ffff:11.1.2.3")));
+
+        // and rules written in the mapped block match v4 peers, mapped or not
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, v4);
+        assert!(mapped.contains(&ip("10.1.2.3")));
+        assert!(mapped.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!mapped.contains(&ip("11.1.2.3")));
+
+        // an address that merely ends in a v4 address isn't mapped
+        assert!(!v4.contains(&ip("::10.1.2.3")));
+    }
+
+    #[test]
+    fn rejects_bad_prefixes () {
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("::/129".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("10.0.0.0/".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("10.0.0.0/-1".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("10.0.0.0/8/8".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("10.0.0.0/x".parse::<Cidr>(), Err(ParseCidrError));
+    }
+
+    #[test]
+    fn rejects_bad_addresses () {
+        assert_eq!("".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("/8".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("10.0.0/8".parse::<Cidr>(), Err(ParseCidrError));
+        assert_eq!("example.com/8".parse::<Cidr>(), Err(ParseCidrError));
+    }
+}
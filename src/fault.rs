@@ -0,0 +1,39 @@
+//! faults that can be injected into established connections, for testing error and partial-write
+//! handling
+//!
+//! Only built with the `fault-injection` feature.  Faults are injected with
+//! Input::InjectFault, and only affect the loop's side of the connection: the peer sees nothing
+//! unusual, apart from what the loop does about the fault.
+
+use std::io;
+
+#[derive(Debug)]
+pub enum Fault {
+    /// stop reading from the connection for this many milliseconds
+    ///
+    /// Data the peer sends in the meantime waits in the socket's buffer.
+    DelayReads(u64),
+
+    /// write at most this many bytes to the socket at a time; None removes the cap, and a cap of 0
+    /// is taken as 1
+    ///
+    /// The rest of the queue is written once the socket is writable again, as it would be after a
+    /// partial write.  Caps are only useful on level-triggered connections, since the socket never
+    /// actually becomes unwritable.
+    CapWrites(Option<usize>),
+
+    /// fail the next read from the connection with this error
+    FailRead(io::Error),
+
+    /// fail the next write to the connection with this error
+    FailWrite(io::Error),
+
+    /// behave as if the peer reset the connection
+    Reset,
+
+    /// behave as if the peer hung up
+    Hangup,
+
+    /// remove any faults that haven't happened yet
+    Clear,
+}
@@ -0,0 +1,116 @@
+use std::net;
+
+use {ProxyHeader, Token};
+
+/// where a connection is in its life
+///
+/// Output::StatisticsResponse carries a connection's current state, and connections that opt in
+/// get an Output::StateChanged each time it changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// an outgoing connection that hasn't connected yet, or is waiting to reconnect; also an
+    /// accepted connection that's held for a decision or still being read a PROXY header from
+    Connecting,
+
+    /// data flows both ways
+    Established,
+
+    /// the write half has been shut down; data is still read, but nothing more can be sent
+    HalfClosedLocal,
+
+    /// the peer has hung up; nothing more is read, but data can still be sent
+    ///
+    /// Only linked connections get here, so that the peer's data can still be forwarded to them.
+    /// Any other connection whose peer hangs up is closed with an Output::Close giving
+    /// CloseReason::Hangup.
+    HalfClosedRemote,
+
+    /// a clean close was requested, or the other side of a link went away, while data was still
+    /// queued; nothing more is read or accepted for sending, and the connection closes once the
+    /// queue has been written, or with an error if that takes longer than its drain timeout
+    Draining,
+
+    /// the connection is gone
+    Closed,
+}
+
+/// a description of an established connection, sent in an Output::InfoResponse
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// the address of the peer
+    pub peer:     net::SocketAddr,
+
+    /// the local address of the connection, if it could be determined
+    pub local:    Option<net::SocketAddr>,
+
+    /// the listener that accepted the connection; None for outgoing connections
+    pub listener: Option<Token>,
+
+    /// the PROXY protocol header the connection started with, if any
+    pub proxy:    Option<ProxyHeader>,
+
+    /// the socket's current options
+    pub options:  SocketOptions,
+
+    /// the kernel's view of the connection; only available on linux
+    pub tcp_info: Option<TcpInfo>,
+}
+
+/// socket options of a connection; each is None if it couldn't be read
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    /// TCP_NODELAY
+    pub nodelay:     Option<bool>,
+
+    /// SO_KEEPALIVE
+    pub keepalive:   Option<bool>,
+
+    /// SO_SNDBUF, in bytes
+    pub send_buffer: Option<usize>,
+
+    /// SO_RCVBUF, in bytes
+    pub recv_buffer: Option<usize>,
+}
+
+/// a subset of the kernel's TCP_INFO for a connection
+#[derive(Debug, Clone, Default)]
+pub struct TcpInfo {
+    /// the TCP state, as the kernel numbers them (1 is ESTABLISHED)
+    pub state:        u8,
+
+    /// retransmits of the current unacknowledged segment
+    pub retransmits:  u8,
+
+    /// the retransmission timeout, in microseconds
+    pub rto_us:       u32,
+
+    /// the maximum segment size for sending
+    pub snd_mss:      u32,
+
+    /// the maximum segment size for receiving
+    pub rcv_mss:      u32,
+
+    /// segments sent but not acknowledged
+    pub unacked:      u32,
+
+    /// segments considered lost
+    pub lost:         u32,
+
+    /// segments being retransmitted
+    pub retrans:      u32,
+
+    /// the path MTU
+    pub pmtu:         u32,
+
+    /// the smoothed round trip time, in microseconds
+    pub rtt_us:       u32,
+
+    /// the round trip time variance, in microseconds
+    pub rttvar_us:    u32,
+
+    /// the congestion window, in segments
+    pub snd_cwnd:     u32,
+
+    /// the total number of retransmitted segments over the connection's life
+    pub total_retrans: u32,
+}
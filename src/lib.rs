@@ -1,22 +1,58 @@
 #[macro_use] extern crate log;
 extern crate mio;
+extern crate libc;
 
+pub mod capture;
+pub mod cidr;
+pub mod info;
 pub mod token_factory;
 pub mod loop_;
 pub mod message;
+pub mod options;
+pub mod proxy;
+pub mod resolver;
+
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+
+#[cfg(feature = "test-support")]
+#[macro_use]
+pub mod testing;
 
 // re-export these types for consumer convenience
 pub use std::sync::mpsc::{Sender, Receiver};
 
+use std::any::Any;
+use std::sync::Arc;
+
 pub type Token = mio::Token;
+
+/// an opaque value attached to a token by the downstream, and handed back with every
+/// OutputMessage for that token
+///
+/// Downcast it with `Any::downcast_ref` to get at the original value.
+pub type UserContext = Arc<Any + Send + Sync>;
 pub use token_factory::Factory as TokenFactory;
 pub use token_factory::SequentialFactory as SequentialTokenFactory;
+pub use token_factory::RecyclingFactory as RecyclingTokenFactory;
+pub use token_factory::NamespacedFactory as NamespacedTokenFactory;
+pub use token_factory::Namespace as TokenNamespace;
 
 pub use message::Output as OutputMessage;
 pub use message::Input as InputMessage;
+pub use message::{CloseReason, Phase, RejectReason};
+
+#[cfg(feature = "fault-injection")]
+pub use fault::Fault;
+
+pub use cidr::Cidr;
+pub use info::{ConnectionInfo, ConnectionState, SocketOptions, TcpInfo};
+pub use proxy::Header as ProxyHeader;
+pub use resolver::{Resolver, SystemResolver};
+pub use options::{AccessRules, ConnectOptions, DownstreamPolicy, ListenOptions, OverLimit, RateLimit, ReconnectPolicy};
 
-pub use loop_::Loop;
-pub use loop_::ClientStatistics;
+pub use loop_::{Loop, LoopHandle};
+pub use loop_::{ClientStatistics, LoopStatistics};
 
 pub fn channel () -> (Sender<OutputMessage>, Receiver<OutputMessage>) {
     use std::sync::mpsc;
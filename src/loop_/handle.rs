@@ -0,0 +1,123 @@
+use mio;
+use std::io;
+use std::net;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use {ClientStatistics, CloseReason, ConnectOptions, InputMessage, ListenOptions, OutputMessage};
+use {Token, TokenFactory};
+
+/// a blocking interface to a running loop
+///
+/// Each call sends its request as an Input::Call with a reply channel of its own and waits for
+/// the answer, so there's no need to pick the reply out of the downstream.  Everything else
+/// still goes to the downstream: data, and the close of a connection once it's established.
+///
+/// Tokens for listeners and connections come from the factory the handle was created with.  It
+/// should share its tokens with the loop's factory, like clones of the factories in this crate
+/// do, so the two never hand out the same token.
+///
+/// Calls give up after 30 seconds by default; see set_timeout.
+#[derive(Clone)]
+pub struct LoopHandle {
+    input:   mio::Sender<InputMessage>,
+    factory: Arc<Mutex<Box<TokenFactory + 'static>>>,
+    timeout: Option<Duration>,
+}
+
+impl LoopHandle {
+    pub fn new<F: TokenFactory + 'static> (input: mio::Sender<InputMessage>, factory: F) -> LoopHandle {
+        LoopHandle {
+            input:   input,
+            factory: Arc::new(Mutex::new(Box::new(factory))),
+            timeout: Some(Duration::from_secs(30)),
+        }
+    }
+
+    /// how long a call waits for its reply, or None to wait for as long as it takes
+    ///
+    /// A call that gives up returns an io::ErrorKind::TimedOut error.  Its request may still be
+    /// carried out, and a reply that comes too late goes to the downstream instead.
+    pub fn set_timeout (&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// listen on an address, returning the listener's token once it's listening
+    pub fn listen (&self, addr: net::SocketAddr) -> Result<Token, io::Error> {
+        let token = self.produce();
+
+        match try!(self.call(InputMessage::ListenRequest {
+            listener: token,
+            addr:     addr,
+            options:  ListenOptions::default(),
+            context:  None,
+        })) {
+            OutputMessage::ListenResponse { listener, .. } => Ok(listener),
+            other => Err(failure(other)),
+        }
+    }
+
+    /// connect to an address, returning the connection's token once it's established
+    pub fn connect (&self, addr: net::SocketAddr) -> Result<Token, io::Error> {
+        let token = self.produce();
+
+        match try!(self.call(InputMessage::ConnectRequest {
+            token:   token,
+            addr:    addr,
+            options: ConnectOptions::default(),
+            context: None,
+        })) {
+            OutputMessage::ConnectResponse { token, .. } => Ok(token),
+            other => Err(failure(other)),
+        }
+    }
+
+    /// get the statistics for a connection
+    pub fn stats (&self, token: Token) -> Result<ClientStatistics, io::Error> {
+        match try!(self.call(InputMessage::StatisticsRequest { token: token })) {
+            OutputMessage::StatisticsResponse { stats, .. } => Ok(stats),
+            other => Err(failure(other)),
+        }
+    }
+
+    /// send a request and wait for the reply to it
+    pub fn call (&self, input: InputMessage) -> Result<OutputMessage, io::Error> {
+        let (reply, answer) = mpsc::channel();
+
+        try!(self.input.send(InputMessage::Call {
+            input: Box::new(input),
+            reply: reply,
+        }).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the loop isn't running")));
+
+        // the loop drops the reply channel if there's nothing to answer
+        let no_reply = || io::Error::new(io::ErrorKind::NotFound, "no reply from the loop");
+
+        match self.timeout {
+            Some(timeout) => match answer.recv_timeout(timeout) {
+                Ok(reply) => Ok(reply),
+                Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the loop to reply")),
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(no_reply()),
+            },
+            None => answer.recv().map_err(|_| no_reply()),
+        }
+    }
+
+    fn produce (&self) -> Token {
+        self.factory.lock().unwrap().produce()
+    }
+}
+
+/// turn a reply that isn't the one asked for into an error
+fn failure (reply: OutputMessage) -> io::Error {
+    match reply {
+        OutputMessage::Close { reason, .. } | OutputMessage::DirtyClose { reason, .. } => match reason {
+            CloseReason::Error(_, e) => e,
+            reason => io::Error::new(io::ErrorKind::ConnectionAborted, format!("closed: {:?}", reason)),
+        },
+
+        OutputMessage::TokenCollision { token, .. } =>
+            io::Error::new(io::ErrorKind::AlreadyExists, format!("token {:?} is already in use", token)),
+
+        other => io::Error::new(io::ErrorKind::Other, format!("unexpected reply {:?}", other)),
+    }
+}
@@ -0,0 +1,70 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// a token bucket, refilled continuously at a fixed rate up to a maximum
+pub struct TokenBucket {
+    rate:     f64,
+    capacity: f64,
+    tokens:   f64,
+    last:     Instant,
+}
+
+impl TokenBucket {
+    /// create a full bucket refilling at `rate` tokens per second, holding at most `capacity`
+    pub fn new (rate: u64, capacity: u64) -> TokenBucket {
+        let capacity = cmp::max(capacity, 1) as f64;
+
+        TokenBucket {
+            rate:     rate as f64,
+            capacity: capacity,
+            tokens:   capacity,
+            last:     Instant::now(),
+        }
+    }
+
+    fn refill (&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// the number of whole tokens currently available
+    pub fn available (&mut self) -> u64 {
+        self.refill();
+        self.tokens as u64
+    }
+
+    /// take `n` tokens if they are all available
+    pub fn try_take (&mut self, n: u64) -> bool {
+        self.refill();
+
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// take `n` tokens, going into debt if there aren't enough
+    pub fn take (&mut self, n: u64) {
+        self.refill();
+        self.tokens -= n as f64;
+    }
+
+    /// how long until `n` tokens will be available
+    pub fn time_until (&mut self, n: u64) -> Duration {
+        self.refill();
+
+        let missing = n as f64 - self.tokens;
+        if missing <= 0.0 || self.rate <= 0.0 {
+            return Duration::from_millis(0);
+        }
+
+        let secs = missing / self.rate;
+        Duration::new(secs as u64, ((secs - secs.floor()) * 1e9) as u32)
+    }
+}
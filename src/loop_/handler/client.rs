@@ -1,14 +1,44 @@
-use mio::{TryRead, TryWrite};
+use mio::TryRead;
 
+use std::cmp;
+use std::collections::VecDeque;
 use std::default::Default;
 use std::io;
 use std::net;
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::Stream;
+use capture::{self, Direction};
+use {ProxyHeader, RateLimit, Token};
+
+use super::{Stream, DRAIN_TIMEOUT_MS};
+use super::socket;
+use super::throttle::{self, Meter, Throttle};
 
 pub enum OperationResult {
     Success(usize),
     WouldBlock,
+
+    /// the write budget ran out with data left over; there'll be more budget after the delay
+    Throttled(Duration),
+}
+
+/// a piece of data queued for writing
+pub enum Chunk {
+    Owned(Vec<u8>),
+
+    /// data that may be queued for other clients too; it's written from the same buffer for all
+    /// of them
+    Shared(Arc<[u8]>),
+}
+
+impl Chunk {
+    fn as_slice (&self) -> &[u8] {
+        match *self {
+            Chunk::Owned(ref data) => data,
+            Chunk::Shared(ref data) => data,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -17,47 +47,224 @@ pub struct Statistics {
     pub bytes_written_queued: u64,
     pub bytes_written: u64,
     pub blocked_writes: u64,
+
+    /// how many writes to the socket took at least one byte
+    pub writes: u64,
+
+    /// bytes read per second, over about the last second
+    pub read_rate: u64,
+
+    /// bytes written per second, over about the last second
+    pub write_rate: u64,
+
+    /// how long reading has been held back by the read limit, in milliseconds
+    pub read_throttled_ms: u64,
+
+    /// how long writing has been held back by the write limit, in milliseconds
+    pub write_throttled_ms: u64,
+}
+
+/// faults injected with Input::InjectFault that haven't happened yet
+#[cfg(feature = "fault-injection")]
+#[derive(Default)]
+pub struct Faults {
+    pub reads_delayed: bool,
+    pub write_cap:     Option<usize>,
+    pub read_error:    Option<io::Error>,
+    pub write_error:   Option<io::Error>,
 }
 
 pub struct Client {
     pub addr:  net::SocketAddr,
+    pub local: Option<net::SocketAddr>,
     stream:    Stream,
     pub stats: Statistics,
 
-    write_buffer: Vec<u8>,
+    /// the listener that accepted this client, if any
+    pub listener: Option<Token>,
+
+    /// the PROXY protocol header the client started with, if any
+    pub proxy: Option<ProxyHeader>,
+
+    /// whether the client is registered edge-triggered
+    pub edge: bool,
+
+    /// whether the client has been registered with the event loop
+    pub registered: bool,
+
+    /// the client's share of the loop's time, relative to other clients with work waiting
+    pub weight: u32,
+
+    /// how long a clean close waits for the write queue to empty before giving up on it
+    pub drain_timeout_ms: u64,
+
+    /// data read before the client was announced to the downstream
+    pub read_ahead: Vec<u8>,
+
+    /// chunks waiting to be written, the offset of the first unwritten byte in the first, and the
+    /// number of bytes left in all of them
+    write_queue:    VecDeque<Chunk>,
+    write_offset:   usize,
+    write_buffered: usize,
+
+    /// where the client's traffic is recorded, if anywhere
+    pub capture: Option<capture::Writer>,
+
+    read_throttle:  Option<Throttle>,
+    write_throttle: Option<Throttle>,
+    read_meter:     Meter,
+    write_meter:    Meter,
+
+    #[cfg(feature = "fault-injection")]
+    pub faults: Faults,
 }
 
 impl Client {
     pub fn new (addr: net::SocketAddr, stream: Stream) -> Client {
+        let local = stream.local_addr().ok();
+
         Client {
             addr: addr,
+            local: local,
             stream: stream,
             stats: Default::default(),
-            write_buffer: Vec::new(),
+            listener: None,
+            proxy: None,
+            edge: false,
+            registered: false,
+            weight: 1,
+            drain_timeout_ms: DRAIN_TIMEOUT_MS,
+            read_ahead: Vec::new(),
+            write_queue: VecDeque::new(),
+            write_offset: 0,
+            write_buffered: 0,
+            capture: None,
+            read_throttle: None,
+            write_throttle: None,
+            read_meter: Meter::new(),
+            write_meter: Meter::new(),
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
         }
     }
 
     pub fn as_ref (&self) -> &Stream {
         &self.stream
     }
+
+    /// the number of bytes queued but not yet written
+    pub fn write_buffered (&self) -> usize {
+        self.write_buffered
+    }
+
+    /// the client's statistics, including its current rates and any throttling in progress
+    pub fn statistics (&self) -> Statistics {
+        let mut stats = self.stats.clone();
+
+        stats.read_rate = self.read_meter.rate();
+        stats.write_rate = self.write_meter.rate();
+        if let Some(ref throttle) = self.read_throttle {
+            stats.read_throttled_ms += throttle::millis(throttle.paused_for());
+        }
+        if let Some(ref throttle) = self.write_throttle {
+            stats.write_throttled_ms += throttle::millis(throttle.paused_for());
+        }
+
+        stats
+    }
+
+    /// limit the rates the client reads and writes at; None removes a limit
+    pub fn set_rate_limits (&mut self, read: Option<RateLimit>, write: Option<RateLimit>) {
+        self.resume_reads();
+        self.resume_writes();
+
+        self.read_throttle = read.map(Throttle::new);
+        self.write_throttle = write.map(Throttle::new);
+    }
+
+    /// whether reading has stopped, for an injected fault or the read limit
+    pub fn reads_paused (&self) -> bool {
+        self.reads_delayed() || self.read_throttle.as_ref().map(|throttle| throttle.paused()).unwrap_or(false)
+    }
+
+    /// stop reading if the read limit's budget has run out, returning how long until there's more
+    pub fn throttle_reads (&mut self) -> Option<Duration> {
+        match self.read_throttle {
+            Some(ref mut throttle) if throttle.budget() == 0 => Some(throttle.pause()),
+            _ => None,
+        }
+    }
+
+    /// start reading again after the read limit stopped it
+    pub fn resume_reads (&mut self) {
+        if let Some(ref mut throttle) = self.read_throttle {
+            self.stats.read_throttled_ms += throttle::millis(throttle.resume());
+        }
+    }
+
+    /// start writing again after the write limit stopped it
+    pub fn resume_writes (&mut self) {
+        if let Some(ref mut throttle) = self.write_throttle {
+            self.stats.write_throttled_ms += throttle::millis(throttle.resume());
+        }
+    }
+
+    /// whether reading has been held off by an injected fault
+    #[cfg(feature = "fault-injection")]
+    pub fn reads_delayed (&self) -> bool {
+        self.faults.reads_delayed
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    pub fn reads_delayed (&self) -> bool {
+        false
+    }
+
+    /// shut down the write half of the connection
+    pub fn shutdown_write (&mut self) -> Result<(), io::Error> {
+        self.stream.shutdown(net::Shutdown::Write)
+    }
 }
 
 // read functions
 impl Client {
-    pub fn try_read_all (&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+    /// read until the socket would block, or `max` bytes have been read
+    pub fn try_read_all (&mut self, max: usize) -> Result<Option<Vec<u8>>, io::Error> {
+        #[cfg(feature = "fault-injection")]
+        {
+            if let Some(e) = self.faults.read_error.take() {
+                return Err(e);
+            }
+        }
+
         let mut ret = Vec::new();
 
-        let mut buf = &mut [0u8; 1024];
+        let mut buf = [0u8; 1024];
 
-        while let Some(read) = try!(self.stream.read_slice(buf)) {
-            if read == 0 {
-                break;
-            }
+        // a throttled client only reads what its budget allows
+        let mut budget = match self.read_throttle {
+            Some(ref mut throttle) => cmp::min(throttle.budget(), max),
+            None => max,
+        };
 
+        while budget > 0 {
+            let len = cmp::min(buf.len(), budget);
+            let read = match try!(self.stream.read_slice(&mut buf[..len])) {
+                None | Some(0) => break,
+                Some(read) => read,
+            };
+
+            budget -= read;
             self.stats.bytes_read += read as u64;
             ret.extend(buf[..read].iter().map(|x| *x));
         }
 
+        if let Some(ref mut throttle) = self.read_throttle {
+            throttle.spend(ret.len());
+        }
+        self.read_meter.record(ret.len());
+        record(&mut self.capture, self.addr, Direction::Read, &ret);
+
         Ok(Some(ret))
     }
 }
@@ -65,22 +272,174 @@ impl Client {
 // write functions
 impl Client {
     pub fn queue_write (&mut self, data: &[u8]) -> Result<(), io::Error> {
-        self.write_buffer.extend(data.iter().map(|x| *x));
-        self.stats.bytes_written_queued += data.len() as u64;
+        self.queue(Chunk::Owned(data.to_vec()));
         Ok(())
     }
 
-    pub fn flush_write (&mut self) -> Result<OperationResult, io::Error> {
-        match try!(self.stream.write_slice(&self.write_buffer[..])) {
-            None => {
-                self.stats.blocked_writes += 1;
-                Ok(OperationResult::WouldBlock)
-            },
-            Some(s) => {
-                self.write_buffer = self.write_buffer[s..].to_vec();
-                self.stats.bytes_written += s as u64;
-                Ok(OperationResult::Success(s))
-            },
+    /// queue a chunk to be written as it is, without copying it
+    pub fn queue (&mut self, chunk: Chunk) {
+        let len = chunk.as_slice().len();
+
+        // an empty chunk would never be written out of the queue
+        if len == 0 {
+            return;
+        }
+
+        self.write_queue.push_back(chunk);
+        self.write_buffered += len;
+        self.stats.bytes_written_queued += len as u64;
+    }
+
+    /// write as much of the buffer as the socket will take, up to `max` bytes
+    ///
+    /// Writing continues until the buffer is empty or the socket would block, which is what an
+    /// edge-triggered registration needs to be told about writability again, or until `max`
+    /// bytes have been written.
+    pub fn flush_write (&mut self, max: usize) -> Result<OperationResult, io::Error> {
+        let mut written = 0;
+        let mut blocked = false;
+        let cap = cmp::min(self.write_limit(), max);
+        let limit = match self.write_throttle {
+            Some(ref mut throttle) => cmp::min(cap, throttle.budget()),
+            None => cap,
+        };
+
+        #[cfg(feature = "fault-injection")]
+        {
+            if let Some(e) = self.faults.write_error.take() {
+                return Err(e);
+            }
+        }
+
+        while written < limit {
+            // gather as many queued chunks as the limit allows into one write, starting from the
+            // unwritten part of the first
+            let result = {
+                let mut slices: Vec<&[u8]> = Vec::new();
+                let mut gathered = 0;
+                let mut offset = self.write_offset;
+
+                for chunk in self.write_queue.iter().take(socket::MAX_IOVECS) {
+                    if gathered == limit - written {
+                        break;
+                    }
+
+                    let data = &chunk.as_slice()[offset..];
+                    let take = cmp::min(data.len(), limit - written - gathered);
+                    slices.push(&data[..take]);
+                    gathered += take;
+                    offset = 0;
+                }
+
+                if slices.is_empty() {
+                    break;
+                }
+
+                let result = socket::write_vectored(&mut self.stream, &slices);
+
+                // captures keep the chunks as they were queued
+                if let Ok(Some(s)) = result {
+                    let mut left = s;
+                    for slice in slices.iter() {
+                        let n = cmp::min(left, slice.len());
+                        if n == 0 {
+                            break;
+                        }
+                        record(&mut self.capture, self.addr, Direction::Write, &slice[..n]);
+                        left -= n;
+                    }
+                }
+
+                try!(result)
+            };
+
+            match result {
+                None => {
+                    self.stats.blocked_writes += 1;
+                    blocked = true;
+                    break;
+                },
+                Some(0) => break,
+                Some(s) => {
+                    self.stats.bytes_written += s as u64;
+                    self.stats.writes += 1;
+                    written += s;
+                    self.consume(s);
+                },
+            }
         }
+
+        if let Some(ref mut throttle) = self.write_throttle {
+            throttle.spend(written);
+        }
+        self.write_meter.record(written);
+
+        // out of budget with more to write: wait for the budget rather than the socket
+        if !blocked && self.write_buffered > 0 {
+            if let Some(ref mut throttle) = self.write_throttle {
+                if throttle.budget() == 0 {
+                    return Ok(OperationResult::Throttled(throttle.pause()));
+                }
+            }
+        }
+        self.resume_writes();
+
+        if blocked && written == 0 {
+            Ok(OperationResult::WouldBlock)
+        } else {
+            Ok(OperationResult::Success(written))
+        }
+    }
+
+    /// drop `written` bytes from the front of the queue, along with any chunks they finish
+    fn consume (&mut self, mut written: usize) {
+        self.write_buffered -= written;
+
+        while written > 0 {
+            let left = match self.write_queue.front() {
+                Some(chunk) => chunk.as_slice().len() - self.write_offset,
+                None => break,
+            };
+
+            if written < left {
+                self.write_offset += written;
+                break;
+            }
+
+            written -= left;
+            self.write_queue.pop_front();
+            self.write_offset = 0;
+        }
+    }
+
+    /// how much of the buffer a flush may write
+    #[cfg(feature = "fault-injection")]
+    fn write_limit (&self) -> usize {
+        match self.faults.write_cap {
+            Some(cap) => cmp::min(cap, self.write_buffered),
+            None      => self.write_buffered,
+        }
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    fn write_limit (&self) -> usize {
+        self.write_buffered
+    }
+}
+
+/// add a chunk to a client's capture, giving up on the capture if it can't be written
+fn record (capture: &mut Option<capture::Writer>, addr: net::SocketAddr, direction: Direction, data: &[u8]) {
+    if data.len() == 0 {
+        return;
+    }
+
+    let result = match *capture {
+        Some(ref mut writer) => writer.record(direction, data),
+        None => return,
+    };
+
+    if let Err(e) = result {
+        error!("failed to record traffic for client at {:?}, no longer recording: {:?}", addr, e);
+        *capture = None;
     }
 }
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::io;
+use mio;
+
+use {CloseReason, ConnectionState, Token};
+
+use super::client::Client;
+use super::slab::{Key, Slab};
+
+/// whether an established connection is waiting to write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteState {
+    /// everything queued has been written
+    Idle,
+
+    /// part of the write buffer is left, and the connection is registered for writable
+    WaitingForWrite,
+
+    /// part of the write buffer is left, but the write limit's budget has run out; a timer
+    /// resumes writing
+    Throttled,
+}
+
+/// an established connection
+pub struct Connection {
+    /// the token the downstream knows the connection by
+    pub token:  Token,
+
+    /// where the connection lives; its event token comes from this
+    pub key:    Key,
+
+    pub state:  ConnectionState,
+    pub write:  WriteState,
+    pub client: Client,
+
+    /// readiness that has been reported but not yet acted on
+    pub ready:  Ready,
+
+    /// the bytes the connection may still move this turn, left over from its last one
+    pub deficit: usize,
+
+    /// whether the connection is in the run queue
+    pub queued: bool,
+
+    /// the deadline for a Draining connection to finish writing
+    pub drain_timer: Option<mio::Timeout>,
+
+    /// what a Draining connection is reported closed for once its queue is written
+    pub drain_reason: Option<CloseReason>,
+
+    /// the timers that bring back a throttled connection's read and write budgets
+    pub read_timer:  Option<mio::Timeout>,
+    pub write_timer: Option<mio::Timeout>,
+
+    /// the timer that ends an injected read delay
+    #[cfg(feature = "fault-injection")]
+    pub resume_timer: Option<mio::Timeout>,
+}
+
+impl Connection {
+    /// take the timers still pending for the connection, so they can be cleared before its token
+    /// goes to something else
+    pub fn take_timers (&mut self) -> Vec<mio::Timeout> {
+        let timers = vec![self.drain_timer.take(), self.read_timer.take(), self.write_timer.take(), self.take_resume_timer()];
+        timers.into_iter().flatten().collect()
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn take_resume_timer (&mut self) -> Option<mio::Timeout> {
+        self.resume_timer.take()
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    fn take_resume_timer (&mut self) -> Option<mio::Timeout> {
+        None
+    }
+}
+
+/// readiness waiting for a connection's turn in the run queue
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ready {
+    pub read:  Option<mio::ReadHint>,
+    pub write: bool,
+}
+
+/// the established connections, stored in a slab so readiness events can find them without
+/// hashing
+///
+/// Downstream tokens are arbitrary, so they're mapped to slab keys through an index; that lookup
+/// is only needed for Input messages.
+pub struct Connections {
+    slab:  Slab<Connection>,
+    index: HashMap<Token, Key>,
+}
+
+impl Connections {
+    pub fn new () -> Connections {
+        Connections {
+            slab:  Slab::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn len (&self) -> usize {
+        self.slab.len()
+    }
+
+    /// store a new connection, or drop it, closing its socket, if there's no room for any more
+    pub fn insert (&mut self, token: Token, client: Client) -> Result<Key, io::Error> {
+        let key = match self.slab.insert_with(|key| Connection {
+            token:  token,
+            key:    key,
+            state:  ConnectionState::Established,
+            write:  WriteState::Idle,
+            client: client,
+            ready:  Ready::default(),
+            deficit: 0,
+            queued: false,
+            drain_timer: None,
+            drain_reason: None,
+            read_timer:  None,
+            write_timer: None,
+            #[cfg(feature = "fault-injection")]
+            resume_timer: None,
+        }) {
+            Some(key) => key,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "too many connections")),
+        };
+
+        self.index.insert(token, key);
+
+        Ok(key)
+    }
+
+    pub fn contains (&self, token: &Token) -> bool {
+        self.index.contains_key(token)
+    }
+
+    pub fn get (&self, token: &Token) -> Option<&Connection> {
+        match self.index.get(token) {
+            Some(&key) => self.slab.get(key),
+            None       => None,
+        }
+    }
+
+    pub fn get_mut (&mut self, token: &Token) -> Option<&mut Connection> {
+        match self.index.get(token) {
+            Some(&key) => self.slab.get_mut(key),
+            None       => None,
+        }
+    }
+
+    /// find a connection from the key in an event token; stale keys find nothing
+    pub fn by_key (&mut self, key: Key) -> Option<&mut Connection> {
+        self.slab.get_mut(key)
+    }
+
+    pub fn remove (&mut self, token: &Token) -> Option<Connection> {
+        match self.index.remove(token) {
+            Some(key) => self.slab.remove(key),
+            None      => None,
+        }
+    }
+
+    pub fn iter_mut<'a> (&'a mut self) -> Box<Iterator<Item = &'a mut Connection> + 'a> {
+        self.slab.iter_mut()
+    }
+
+    pub fn clear (&mut self) {
+        self.slab.clear();
+        self.index.clear();
+    }
+}
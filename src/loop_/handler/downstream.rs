@@ -0,0 +1,244 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{Sender, SendError};
+
+use {DownstreamPolicy, OutputMessage, Token};
+
+/// where output goes, and what happens to it when the receiving end goes away
+pub struct Downstream {
+    sender:       Sender<OutputMessage>,
+    fallback:     Option<Sender<OutputMessage>>,
+    pub policy:   DownstreamPolicy,
+
+    /// tokens whose output goes somewhere other than the main downstream
+    routes:       HashMap<Token, Sender<OutputMessage>>,
+
+    /// where the replies to Input::Call requests go, by token, with the id of each call
+    ///
+    /// A token can have more than one call waiting, such as a request for statistics while a
+    /// connect is still going; the newest call gets the next answer, since anything but a connect
+    /// is answered as soon as it's processed.
+    replies:      HashMap<Token, Vec<(u64, Sender<OutputMessage>)>>,
+    next_call:    u64,
+
+    /// output held while there's no downstream to take it
+    backlog:      VecDeque<OutputMessage>,
+    disconnected: bool,
+}
+
+impl Downstream {
+    pub fn new (sender: Sender<OutputMessage>) -> Downstream {
+        Downstream {
+            sender:       sender,
+            fallback:     None,
+            policy:       DownstreamPolicy::Shutdown,
+            routes:       HashMap::new(),
+            replies:      HashMap::new(),
+            next_call:    0,
+            backlog:      VecDeque::new(),
+            disconnected: false,
+        }
+    }
+
+    /// send a message, applying the policy if the downstream has gone away
+    ///
+    /// An error means there's nowhere left for output to go, and the loop should shut down.
+    pub fn send (&mut self, message: OutputMessage) -> Result<(), SendError<OutputMessage>> {
+        let message = match message.token() {
+            Some(token) => match self.reply(token, message).or_else(|message| self.route(token, message)) {
+                Ok(_) => return Ok(()),
+                Err(message) => message,
+            },
+            None => message,
+        };
+
+        if self.disconnected {
+            return self.hold(message);
+        }
+
+        let message = match self.sender.send(message) {
+            Ok(_) => return Ok(()),
+            Err(SendError(message)) => message,
+        };
+
+        warn!("downstream disconnected");
+
+        match self.policy {
+            DownstreamPolicy::Shutdown => Err(SendError(message)),
+
+            DownstreamPolicy::Fallback => match self.fallback.take() {
+                Some(fallback) => {
+                    info!("switching to the fallback downstream");
+                    self.sender = fallback;
+                    self.send(message)
+                },
+                None => Err(SendError(message)),
+            },
+
+            DownstreamPolicy::Buffer(_) => {
+                self.disconnected = true;
+                self.hold(message)
+            },
+        }
+    }
+
+    /// answer an Input::Call for the token, handing the message back if it isn't an answer
+    fn reply (&mut self, token: Token, message: OutputMessage) -> Result<(), OutputMessage> {
+        if !message.is_reply() {
+            return Err(message);
+        }
+
+        let reply = match self.replies.get_mut(&token) {
+            Some(calls) => calls.pop().map(|(_, reply)| reply),
+            None => None,
+        };
+
+        if self.replies.get(&token).map(|calls| calls.is_empty()).unwrap_or(false) {
+            self.replies.remove(&token);
+        }
+
+        match reply {
+            // the caller gave up waiting, so the downstream may as well have it
+            Some(reply) => reply.send(message).map_err(|SendError(message)| message),
+            None => Err(message),
+        }
+    }
+
+    /// send the next answer for a token down `reply`, returning an id for the call
+    pub fn expect_reply (&mut self, token: Token, reply: Sender<OutputMessage>) -> u64 {
+        let call = self.next_call;
+        self.next_call += 1;
+
+        self.replies.entry(token).or_insert_with(Vec::new).push((call, reply));
+
+        call
+    }
+
+    /// stop waiting for the answer to a call, which drops its reply channel; other calls for the
+    /// token keep waiting
+    pub fn cancel_reply (&mut self, token: &Token, call: u64) {
+        if let Some(calls) = self.replies.get_mut(token) {
+            calls.retain(|&(id, _)| id != call);
+        }
+
+        if self.replies.get(token).map(|calls| calls.is_empty()).unwrap_or(false) {
+            self.replies.remove(token);
+        }
+    }
+
+    /// send a message down its token's own downstream, handing it back if there isn't one
+    fn route (&mut self, token: Token, message: OutputMessage) -> Result<(), OutputMessage> {
+        let message = match self.routes.get(&token) {
+            Some(sender) => match sender.send(message) {
+                Ok(_) => return Ok(()),
+                Err(SendError(message)) => message,
+            },
+            None => return Err(message),
+        };
+
+        warn!("downstream for {:?} disconnected, using the main downstream", token);
+        self.routes.remove(&token);
+        Err(message)
+    }
+
+    fn hold (&mut self, message: OutputMessage) -> Result<(), SendError<OutputMessage>> {
+        if let DownstreamPolicy::Buffer(limit) = self.policy {
+            if self.backlog.len() < limit {
+                self.backlog.push_back(message);
+                return Ok(());
+            }
+        }
+
+        error!("no downstream, and {:?} messages are already held", self.backlog.len());
+        Err(SendError(message))
+    }
+
+    /// start sending to a new downstream, delivering anything held in the meantime first
+    pub fn replace (&mut self, sender: Sender<OutputMessage>) {
+        if !self.disconnected {
+            match self.sender.send(OutputMessage::Handoff { token: None }) {
+                Err(_) => {},
+                Ok(_) => {},
+            }
+        }
+
+        self.sender = sender;
+        self.disconnected = false;
+
+        while let Some(message) = self.backlog.pop_front() {
+            if let Err(SendError(message)) = self.sender.send(message) {
+                // gone already; keep holding on until the next one
+                self.backlog.push_front(message);
+                self.disconnected = true;
+                return;
+            }
+        }
+    }
+
+    /// send a token's output somewhere else, or back to the main downstream
+    ///
+    /// The downstream the token's output went to until now gets an Output::Handoff.
+    pub fn reroute (&mut self, token: Token, sender: Option<Sender<OutputMessage>>) -> Result<(), SendError<OutputMessage>> {
+        let result = match self.routes.remove(&token) {
+            Some(old) => match old.send(OutputMessage::Handoff { token: Some(token) }) {
+                Err(_) => Ok(()),
+                Ok(_) => Ok(()),
+            },
+            None => self.send(OutputMessage::Handoff { token: Some(token) }),
+        };
+
+        if let Some(sender) = sender {
+            self.routes.insert(token, sender);
+        }
+
+        result
+    }
+
+    /// forget a token's downstream once the token is done with
+    pub fn forget (&mut self, token: &Token) {
+        self.routes.remove(token);
+    }
+
+    pub fn set_fallback (&mut self, sender: Sender<OutputMessage>) {
+        self.fallback = Some(sender);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use mio;
+
+    use OutputMessage;
+
+    use super::Downstream;
+
+    #[test]
+    fn calls_on_one_token_keep_their_replies () {
+        let (sender, output) = mpsc::channel();
+        let mut downstream = Downstream::new(sender);
+        let token = mio::Token(1);
+
+        // a call answered later, and one answered straight away for the same token
+        let (first, first_answer) = mpsc::channel();
+        let (second, second_answer) = mpsc::channel();
+        downstream.expect_reply(token, first);
+        let call = downstream.expect_reply(token, second);
+
+        downstream.send(OutputMessage::TokenCollision { token: token, context: None }).unwrap();
+        downstream.cancel_reply(&token, call);
+        match second_answer.try_recv() {
+            Ok(OutputMessage::TokenCollision { .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        downstream.send(OutputMessage::ListenResponse { listener: token, context: None }).unwrap();
+        match first_answer.try_recv() {
+            Ok(OutputMessage::ListenResponse { .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // with both calls answered, the next message goes to the downstream
+        downstream.send(OutputMessage::TokenCollision { token: token, context: None }).unwrap();
+        assert!(output.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,29 @@
+use Token;
+
+/// one side of a pair of connections joined by an Input::Link
+///
+/// Each of the two linked tokens gets its own Link, pointing at the other.
+pub struct Link {
+    /// the token on the other side of the link
+    pub peer: Token,
+
+    /// whether reads on this side are paused because the peer's write buffer is full
+    pub paused: bool,
+
+    /// whether this side has hung up; nothing more will be read from it
+    pub read_closed: bool,
+
+    /// whether the write half of this side has been shut down
+    pub write_closed: bool,
+}
+
+impl Link {
+    pub fn new (peer: Token) -> Link {
+        Link {
+            peer: peer,
+            paused: false,
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+}
@@ -1,16 +1,142 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
+use std::net;
 use mio;
 use mio::tcp::TcpListener;
 
+use options::ListenOptions;
+use message::RejectReason;
+
+use super::bucket::TokenBucket;
+
+/// the first pause after running out of descriptors, in milliseconds
+const BACKOFF_MIN_MS: u64 = 10;
+
+/// pauses double each time accepting fails again, up to this many milliseconds
+const BACKOFF_MAX_MS: u64 = 1_000;
+
 pub struct Listener {
-    listener: mio::NonBlock<TcpListener>,
+    listener:       mio::NonBlock<TcpListener>,
+    pub options:    ListenOptions,
+
+    /// whether the listener is currently registered for incoming connections
+    pub accepting:  bool,
+
+    clients:        usize,
+    clients_per_ip: HashMap<net::IpAddr, usize>,
+    accept_bucket:  Option<TokenBucket>,
+
+    /// how long accepting was last paused for after running out of descriptors, or 0 if the last
+    /// accept worked
+    backoff_ms:     u64,
+
+    /// the timer that resumes accepting once paused by the accept rate or a lack of descriptors
+    pub resume_timer: Option<mio::Timeout>,
+
+    /// the timer that carries on draining the backlog of an edge-triggered listener
+    pub accept_timer: Option<mio::Timeout>,
 }
 
 impl Listener {
-    pub fn new (listener: mio::NonBlock<TcpListener>) -> Listener {
+    pub fn new (listener: mio::NonBlock<TcpListener>, options: ListenOptions) -> Listener {
+        let accept_bucket = options.accept_rate.map(|rate| TokenBucket::new(rate as u64, options.accept_burst as u64));
+
         Listener {
-            listener: listener,
+            listener:       listener,
+            options:        options,
+            accepting:      true,
+            clients:        0,
+            clients_per_ip: HashMap::new(),
+            accept_bucket:  accept_bucket,
+            backoff_ms:     0,
+            resume_timer:   None,
+            accept_timer:   None,
+        }
+    }
+
+    /// check the limits that apply before a connection is accepted
+    pub fn check_limits (&mut self) -> Option<RejectReason> {
+        if let Some(max) = self.options.max_clients {
+            if self.clients >= max {
+                return Some(RejectReason::MaxClients);
+            }
+        }
+
+        if let Some(ref mut bucket) = self.accept_bucket {
+            if bucket.available() < 1 {
+                return Some(RejectReason::AcceptRate);
+            }
+        }
+
+        None
+    }
+
+    /// check the peer against the access rules and per-address limit, and record the client if
+    /// it's allowed in
+    pub fn admit (&mut self, addr: &net::SocketAddr) -> Option<RejectReason> {
+        if !self.options.access.permits(&addr.ip()) {
+            return Some(RejectReason::AccessDenied);
         }
+
+        let count = self.clients_per_ip.get(&addr.ip()).map(|x| *x).unwrap_or(0);
+
+        if let Some(max) = self.options.max_clients_per_ip {
+            if count >= max {
+                return Some(RejectReason::MaxClientsPerIp);
+            }
+        }
+
+        if let Some(ref mut bucket) = self.accept_bucket {
+            bucket.take(1);
+        }
+
+        self.clients += 1;
+        self.clients_per_ip.insert(addr.ip(), count + 1);
+
+        None
+    }
+
+    /// forget a client previously let in by admit
+    pub fn release (&mut self, addr: &net::SocketAddr) {
+        self.clients -= 1;
+
+        let remaining = match self.clients_per_ip.get_mut(&addr.ip()) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return,
+        };
+
+        if remaining == 0 {
+            self.clients_per_ip.remove(&addr.ip());
+        }
+    }
+
+    /// how long to pause accepting after running out of descriptors, longer each time it happens
+    /// without a successful accept in between
+    pub fn back_off (&mut self) -> u64 {
+        self.backoff_ms = if self.backoff_ms == 0 {
+            BACKOFF_MIN_MS
+        } else {
+            cmp::min(self.backoff_ms * 2, BACKOFF_MAX_MS)
+        };
+
+        self.backoff_ms
+    }
+
+    /// note that a connection was accepted, so the next failure starts backing off afresh
+    pub fn accepted (&mut self) {
+        self.backoff_ms = 0;
+    }
+
+    /// how long until the accept rate allows another connection
+    pub fn accept_delay_ms (&mut self) -> Option<u64> {
+        self.accept_bucket.as_mut().map(|bucket| {
+            let delay = bucket.time_until(1);
+            delay.as_secs() * 1000 + (delay.subsec_nanos() as u64 + 999_999) / 1_000_000
+        })
     }
 }
 
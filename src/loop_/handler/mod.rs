@@ -1,22 +1,112 @@
-use std::collections::HashMap;
-use std::{convert, io, net};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{cmp, io, mem, net};
+use std::sync::Arc;
 use std::sync::mpsc::Sender;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use mio;
 use mio::tcp::TcpStream;
 
-use self::client::Client;
+use self::client::{Chunk, Client};
+use self::connection::{Connection, Connections, Ready, WriteState};
+use self::downstream::Downstream;
+use self::link::Link;
 use self::listener::Listener;
+use self::pending::Pending;
+use self::pool::ResolverPool;
+use self::reconnect::{Reconnect, Target};
+use self::slab::Key;
 
+use capture;
+use info::{ConnectionInfo, ConnectionState};
 use loop_::EventLoop;
+use proxy;
+use resolver::{self, Resolver};
 use {InputMessage, OutputMessage};
-use {Token, TokenFactory};
+use {AccessRules, CloseReason, ConnectOptions, DownstreamPolicy, ListenOptions, OverLimit, Phase, ProxyHeader, RateLimit};
+use RejectReason;
+use {Token, TokenFactory, UserContext};
 
+#[cfg(feature = "fault-injection")]
+use fault::Fault;
+
+mod bucket;
 mod client;
+mod connection;
+mod downstream;
+mod link;
 mod listener;
+mod pending;
+mod pool;
+mod reconnect;
+mod slab;
+mod socket;
+mod throttle;
 
 pub use self::client::Statistics as ClientStatistics;
 pub type Stream   = mio::NonBlock<TcpStream>;
 
+/// a linked client never has more than this many bytes queued from its peer; reads from the peer
+/// are capped to the room left, and paused once there's none
+const LINK_HIGH_WATER: usize = 256 * 1024;
+
+/// paused reads resume once the linked client's queue drains below this many bytes
+const LINK_LOW_WATER:  usize = 64 * 1024;
+
+/// how long a clean close waits for an outgoing connection's queue to be written, unless its
+/// options say otherwise
+const DRAIN_TIMEOUT_MS: u64 = 30_000;
+
+/// the bytes a connection of weight 1 may read and write in each turn from the run queue
+const QUANTUM: usize = 16 * 1024;
+
+/// once a pass over the run queue has moved this many bytes, the rest waits for the next pass so
+/// that new events and Input messages get a look in
+const PASS_BUDGET: usize = 1024 * 1024;
+
+/// statistics for the loop as a whole
+#[derive(Default, Debug, Clone)]
+pub struct LoopStatistics {
+    pub connections_accepted: u64,
+    pub connections_rejected: u64,
+    pub connections_denied: u64,
+}
+
+/// timers scheduled on the event loop
+#[derive(Debug)]
+pub enum Timeout {
+    /// resume accepting on a listener that was paused by its accept rate
+    ResumeAccept(Token),
+
+    /// give up on the downstream deciding whether to accept a held client
+    AcceptDecision(Token),
+
+    /// give up on a client of a proxy_protocol listener sending its PROXY header
+    ProxyHeader(Token),
+
+    /// keep draining the backlog of an edge-triggered listener
+    Accept(Token),
+
+    /// give up on the current address of an outgoing connection
+    ConnectAttempt(Token),
+
+    /// try to re-establish an outgoing connection
+    Reconnect(Token),
+
+    /// a connection held back by its read limit has budget to read again
+    ReadBudget(Token),
+
+    /// a connection held back by its write limit has budget to write again
+    WriteBudget(Token),
+
+    /// give up on a Draining connection writing the rest of its queue
+    Drain(Token),
+
+    /// start reading from a connection again after an injected delay
+    #[cfg(feature = "fault-injection")]
+    ResumeReads(Token),
+}
+
 #[derive(Debug)]
 enum Action {
     None,
@@ -29,8 +119,7 @@ enum Error {
 
     // these errors do not constitute shutting down of the loop, but do cause a client dirty
     // disconnect
-    Io(io::Error),
-    ClientError,
+    Failed(Phase, io::Error),
 
     // these errors constitute a clean client disconnect
     ClientDisconnect,
@@ -39,136 +128,411 @@ enum Error {
     DownstreamDisconnect,
 }
 
-impl convert::From<io::Error> for Error {
-    fn from (x: io::Error) -> Error {
-        Error::Io(x)
+fn poll_opt (edge: bool) -> mio::PollOpt {
+    if edge {
+        mio::PollOpt::edge()
+    } else {
+        mio::PollOpt::level()
     }
 }
 
-fn new_client (clients: &mut HashMap<Token, (bool, Client)>, eloop: &mut EventLoop, token: Token, addr: net::SocketAddr, stream: Stream) -> Result<(), Error> {
-    let client = Client::new(addr.clone(), stream);
+fn client_interest (state: ConnectionState, write: WriteState, link: Option<&Link>) -> mio::Interest {
+    let reading = match state {
+        ConnectionState::Established | ConnectionState::HalfClosedLocal => match link {
+            Some(link) => !link.paused && !link.read_closed,
+            None       => true,
+        },
+        _ => false,
+    };
+
+    let mut interest = mio::Interest::error();
+    if reading {
+        interest = interest | mio::Interest::readable() | mio::Interest::hup();
+    }
+    if write == WriteState::WaitingForWrite {
+        interest = interest | mio::Interest::writable();
+    }
 
-    info!("new client at {:?}", addr);
+    interest
+}
 
-    // register with the event loop
-    match eloop.register_opt(
-        client.as_ref(),
-        token,
-        mio::Interest::readable() | mio::Interest::hup() | mio::Interest::error(),
-        mio::PollOpt::level()
-        ) {
-            Err(e) => {
-                error!("failed to register client at {:?}: {:?}", addr, e);
-                return Err(Error::AcceptFailed);
-            },
-            _ => {},
+fn register_client (eloop: &mut EventLoop, token: Token, client: &mut Client, interest: mio::Interest) -> Result<(), Error> {
+    // clients that had to read a PROXY header before being announced are already registered
+    let result = if client.registered {
+        eloop.reregister(client.as_ref(), token, interest, poll_opt(client.edge))
+    } else {
+        eloop.register_opt(client.as_ref(), token, interest, poll_opt(client.edge))
+    };
+
+    match result {
+        Err(e) => {
+            error!("failed to register client at {:?}: {:?}", client.addr, e);
+            Err(Error::Failed(Phase::Register, e))
+        },
+        _ => {
+            client.registered = true;
+            Ok(())
+        },
+    }
+}
+
+fn outgoing_client (addr: net::SocketAddr, stream: Stream, options: ConnectOptions) -> Result<Client, Error> {
+    let mut client = Client::new(addr, stream);
+    client.edge = options.edge_triggered;
+    client.set_rate_limits(options.read_limit, options.write_limit);
+    client.weight = cmp::max(options.weight, 1);
+    client.drain_timeout_ms = options.drain_timeout_ms.unwrap_or(DRAIN_TIMEOUT_MS);
+
+    // the PROXY header has to go out before anything else
+    if let Some(mut header) = options.proxy_header {
+        if header.destination.is_none() {
+            header.destination = Some(addr);
         }
 
-    // stash it in the HashMap
-    clients.insert(token, (false, client));
+        debug!("sending PROXY header to {:?}: {:?}", addr, header);
+        let encoded = try!(header.encode().map_err(|e| Error::Failed(Phase::Write, e)));
+        try!(client.queue_write(&encoded).map_err(|e| Error::Failed(Phase::Write, e)));
+    }
+
+    Ok(client)
+}
+
+/// start recording a client's traffic in a capture directory, if there is one
+fn start_capture (captures: &mut capture::Recorder, directory: Option<&PathBuf>, token: Token) -> Option<capture::Writer> {
+    let directory = match directory {
+        Some(directory) => directory,
+        None => return None,
+    };
+
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let millis = since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000;
+    let path = directory.join(format!("{}-{}.cap", token.0, millis));
+
+    match captures.create(&path) {
+        Ok(writer) => {
+            debug!("recording {:?} to {:?}", token, path);
+            Some(writer)
+        },
+        Err(e) => {
+            error!("failed to create capture file {:?}: {:?}", path, e);
+            None
+        },
+    }
+}
+
+fn new_client (clients: &mut Connections, eloop: &mut EventLoop, token: Token, client: Client) -> Result<(), Error> {
+    info!("new client at {:?}", client.addr);
+
+    // stash it in the slab; its events come back under the slab key
+    let key = match clients.insert(token, client) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("no room for client {:?}: {:?}", token, e);
+            return Err(Error::Failed(Phase::Register, e));
+        },
+    };
+
+    // register with the event loop
+    let result = match clients.by_key(key) {
+        Some(&mut Connection { ref mut client, .. }) => register_client(eloop, key.token(), client, client_interest(ConnectionState::Established, WriteState::Idle, None)),
+        None => Ok(()),
+    };
+
+    if result.is_err() {
+        clients.remove(&token);
+    }
 
-    Ok(())
+    result
 }
 
 pub struct Handler {
-    pending_clients: HashMap<Token, (net::SocketAddr, Stream)>,
-    clients:         HashMap<Token, (bool, Client)>,
+    /// connections waiting on their host to be resolved, with the id of the request made for them
+    resolving:       HashMap<Token, (u64, ConnectOptions)>,
+    next_request:    u64,
+    pending_clients: HashMap<Token, Pending>,
+    reconnects:      HashMap<Token, Reconnect>,
+    clients:         Connections,
+    held_clients:    HashMap<Token, (Client, Option<mio::Timeout>)>,
+    awaiting_header: HashMap<Token, (Client, Vec<u8>, Option<mio::Timeout>)>,
     listeners:       HashMap<Token, Listener>,
-    downstream:      Sender<OutputMessage>,
+    links:           HashMap<Token, Link>,
+    contexts:        HashMap<Token, UserContext>,
+    reporting_state: HashSet<Token>,
+    stats:           LoopStatistics,
+    downstream:      Downstream,
     factory:         Box<TokenFactory + 'static>,
+    resolvers:       ResolverPool,
+    captures:        capture::Recorder,
+
+    /// established connections with readiness waiting for their turn
+    run_queue:       VecDeque<Key>,
+
+    /// the loop's own channel, to give the run queue a turn once the current events are handled
+    wake:            mio::Sender<InputMessage>,
+    run_pending:     bool,
 }
 
 impl Handler {
-    pub fn new<F: TokenFactory + 'static> (
+    pub fn new<F: TokenFactory + 'static, R: Resolver + 'static> (
         factory:    F,
         downstream: Sender<OutputMessage>,
+        resolver:   R,
+        wake:       mio::Sender<InputMessage>,
         ) -> Handler {
             Handler {
+                resolving:       HashMap::new(),
+                next_request:    0,
                 pending_clients: HashMap::new(),
-                clients:         HashMap::new(),
+                reconnects:      HashMap::new(),
+                clients:         Connections::new(),
+                held_clients:    HashMap::new(),
+                awaiting_header: HashMap::new(),
                 listeners:       HashMap::new(),
-                downstream:      downstream,
+                links:           HashMap::new(),
+                contexts:        HashMap::new(),
+                reporting_state: HashSet::new(),
+                stats:           Default::default(),
+                downstream:      Downstream::new(downstream),
                 factory:         Box::new(factory),
+                resolvers:       ResolverPool::new(Arc::new(resolver), wake.clone()),
+                captures:        capture::Recorder::new(),
+                run_queue:       VecDeque::new(),
+                wake:            wake,
+                run_pending:     false,
+            }
+        }
+
+    pub fn set_downstream_policy (&mut self, policy: DownstreamPolicy) {
+        self.downstream.policy = policy;
+    }
+
+    /// whether a token already belongs to a listener or connection, in any state
+    fn token_in_use (&self, token: &Token) -> bool {
+        slab::is_reserved(*token) ||
+            self.listeners.contains_key(token) ||
+            self.clients.contains(token) ||
+            self.pending_clients.contains_key(token) ||
+            self.resolving.contains_key(token) ||
+            self.reconnects.contains_key(token) ||
+            self.held_clients.contains_key(token) ||
+            self.awaiting_header.contains_key(token)
+    }
+
+    /// refuse a request for a token that's already in use
+    fn token_collision (&mut self, token: Token, context: Option<UserContext>) -> Result<Action, Error> {
+        warn!("received request for token {:?}, which is already in use", token);
+
+        match self.downstream.send(OutputMessage::TokenCollision {
+            token:   token,
+            context: context,
+        }) {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_)  => Ok(Action::None),
+        }
+    }
+
+    /// the user context attached to a token, if any
+    fn context (&self, token: &Token) -> Option<UserContext> {
+        self.contexts.get(token).cloned()
+    }
+
+    fn set_context (&mut self, token: Token, context: Option<UserContext>) {
+        match context {
+            Some(context) => { self.contexts.insert(token, context); },
+            None          => { self.contexts.remove(&token); },
+        }
+    }
+
+    /// the state of a connection, wherever it's kept
+    fn state (&self, token: &Token) -> Option<ConnectionState> {
+        if let Some(connection) = self.clients.get(token) {
+            Some(connection.state)
+        } else if self.pending_clients.contains_key(token) ||
+            self.resolving.contains_key(token) ||
+            self.reconnects.contains_key(token) ||
+            self.held_clients.contains_key(token) ||
+            self.awaiting_header.contains_key(token) {
+                Some(ConnectionState::Connecting)
+        } else {
+            None
+        }
+    }
+
+    /// move an established connection to a new state
+    fn set_state (&mut self, token: Token, state: ConnectionState) -> Result<(), Error> {
+        match self.clients.get_mut(&token) {
+            Some(connection) => {
+                if connection.state == state {
+                    return Ok(());
+                }
+
+                debug!("{:?} is now {:?}", token, state);
+                connection.state = state;
+            },
+            None => return Ok(()),
+        }
+
+        self.report_state(token, state)
+    }
+
+    /// record one half of a connection closing
+    fn half_close (&mut self, token: Token, remote: bool) -> Result<(), Error> {
+        let state = match (self.state(&token), remote) {
+            (Some(ConnectionState::Established), true)  => ConnectionState::HalfClosedRemote,
+            (Some(ConnectionState::Established), false) => ConnectionState::HalfClosedLocal,
+
+            // once both halves are closed, the connection is about to be closed altogether
+            _ => return Ok(()),
+        };
+
+        self.set_state(token, state)
+    }
+
+    /// send an Output::StateChanged, if the connection asked for them
+    fn report_state (&mut self, token: Token, state: ConnectionState) -> Result<(), Error> {
+        if !self.reporting_state.contains(&token) {
+            return Ok(());
+        }
+
+        match self.downstream.send(OutputMessage::StateChanged {
+            token:   token,
+            state:   state,
+            context: self.context(&token),
+        }) {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_)  => Ok(()),
+        }
+    }
+
+    fn reregister_client (&self, eloop: &mut EventLoop, token: Token) -> Result<(), Error> {
+        if let Some(&Connection { key, state, write, ref client, .. }) = self.clients.get(&token) {
+            let mut interest = client_interest(state, write, self.links.get(&token));
+            if client.reads_paused() {
+                interest.remove(mio::Interest::readable() | mio::Interest::hup());
             }
+
+            match eloop.reregister(
+                client.as_ref(),
+                key.token(),
+                interest,
+                poll_opt(client.edge)
+                ) {
+                    Err(e) => {
+                        error!("failed to reregister client at {:?}: {:?}", client.addr, e);
+                        return Err(Error::Failed(Phase::Register, e));
+                    },
+                    _ => {},
+                }
         }
 
-    fn try_flush (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
-        if let Some(&mut (ref mut waiting_for_write, ref mut client)) = self.clients.get_mut(&token) {
+        Ok(())
+    }
+
+    /// flush a client, writing at most `max` bytes
+    fn try_flush_up_to (&mut self, eloop: &mut EventLoop, token: Token, max: usize) -> Result<Action, Error> {
+        let (changed, buffered, state) = if let Some(&mut Connection { state, ref mut write, ref mut client, ref mut write_timer, .. }) = self.clients.get_mut(&token) {
             // try to flush the client
-            match client.flush_write() {
+            let next = match client.flush_write(max) {
                 // the write failed
                 Err(e) => {
                     error!("error flushing write for client at {:?}: {:?}", client.addr, e);
-                    return Err(Error::ClientError);
+                    return Err(Error::Failed(Phase::Write, e));
                 },
 
-                // the write would've blocked
-                Ok(client::OperationResult::WouldBlock) => {
-
-                    // were we waiting for it to be writeable?
-                    if ! *waiting_for_write {
-                        // reregister it with the writeable interest
-                        match eloop.reregister(
-                            client.as_ref(),
-                            token,
-                            mio::Interest::readable() | mio::Interest::writable() | mio::Interest::hup() | mio::Interest::error(),
-                            mio::PollOpt::level()
-                            ) {
-                                Err(e) => {
-                                    error!("failed to reregister client at {:?} for writeable: {:?}", client.addr, e);
-                                    return Err(Error::Io(e));
-                                },
-                                _ => {},
-                            }
-                        *waiting_for_write = true;
-                    }
-
-                    Ok(Action::None)
-                },
+                // the write would've blocked, so wait for it to be writable
+                Ok(client::OperationResult::WouldBlock) => WriteState::WaitingForWrite,
 
-                // the write didn't block, so make sure that it's not waiting for a write
+                // the write didn't block, so only keep waiting if some of the buffer is left
                 Ok(client::OperationResult::Success(size)) => {
                     trace!("wrote {:?} bytes for client at {:?}", size, client.addr);
+                    if client.write_buffered() > 0 { WriteState::WaitingForWrite } else { WriteState::Idle }
+                },
 
-                    if *waiting_for_write {
-                        // reregister it without the writeable interest
-                        match eloop.reregister(
-                            client.as_ref(),
-                            token,
-                            mio::Interest::readable() | mio::Interest::hup() | mio::Interest::error(),
-                            mio::PollOpt::level()
-                            ) {
-                                Err(e) => {
-                                    error!("failed to reregister client at {:?} for not writeable: {:?}", client.addr, e);
-                                    return Err(Error::Io(e));
-                                },
-                                _ => {},
-                            }
-                        *waiting_for_write = false;
+                // out of budget, so wait for a timer rather than the socket
+                Ok(client::OperationResult::Throttled(delay)) => {
+                    if *write != WriteState::Throttled {
+                        debug!("throttling writes to {:?} for {:?}", token, delay);
+                        if let Some(timeout) = write_timer.take() {
+                            eloop.clear_timeout(timeout);
+                        }
+                        match eloop.timeout_ms(Timeout::WriteBudget(token), cmp::max(throttle::millis(delay), 1)) {
+                            Err(e) => error!("failed to schedule resuming writes to {:?}: {:?}", token, e),
+                            Ok(timeout) => *write_timer = Some(timeout),
+                        }
                     }
-
-                    Ok(Action::None)
+                    WriteState::Throttled
                 },
-            }
+            };
+
+            let changed = next != *write;
+            *write = next;
+
+            (changed, client.write_buffered(), state)
         } else {
             warn!("received flush request for stale token {:?}", token);
-            Ok(Action::None)
+            return Ok(Action::None);
+        };
+
+        if changed {
+            try!(self.reregister_client(eloop, token));
+        }
+
+        // a draining client is closed as soon as its queue is written, with its write half shut
+        // down first so the peer sees the end of the data rather than a reset
+        if state == ConnectionState::Draining && buffered == 0 {
+            debug!("{:?} has drained", token);
+
+            let reason = match self.clients.get_mut(&token) {
+                Some(&mut Connection { ref mut client, ref mut drain_reason, .. }) => {
+                    try!(client.shutdown_write().map_err(|e| Error::Failed(Phase::Write, e)));
+                    drain_reason.take().unwrap_or(CloseReason::Requested)
+                },
+                None => CloseReason::Requested,
+            };
+
+            return self.proc_close(eloop, token, false, reason);
+        }
+
+        if self.links.contains_key(&token) {
+            if buffered < LINK_LOW_WATER {
+                self.resume_peer(eloop, token);
+            }
+
+            if buffered == 0 {
+                return self.close_write_if_drained(token);
+            }
         }
+
+        Ok(Action::None)
     }
 
-    fn proc_listen_request (&mut self, eloop: &mut EventLoop, token: Token, addr: net::SocketAddr) -> Result<Action, Error> {
-        let listener = try!(mio::tcp::listen(&addr));
+    fn proc_listen_request (&mut self, eloop: &mut EventLoop, token: Token, addr: net::SocketAddr, options: ListenOptions, context: Option<UserContext>) -> Result<Action, Error> {
+        if self.token_in_use(&token) {
+            return self.token_collision(token, context);
+        }
+
+        self.set_context(token, context);
+
+        let listener = match mio::tcp::listen(&addr) {
+            Err(e) => {
+                error!("failed to listen on {:?}: {:?}", addr, e);
+                try!(self.send_close(token, true, CloseReason::Error(Phase::Register, e)));
+                return Ok(Action::None);
+            },
+            Ok(listener) => listener,
+        };
 
         // register it in the loop
         match eloop.register_opt(
             &listener,
             token,
             mio::Interest::readable() | mio::Interest::hup() | mio::Interest::error(),
-            mio::PollOpt::level()
+            poll_opt(options.edge_triggered)
             ) {
                 Err(e) => {
                     error!("failed to register listener at {:?} for readable: {:?}", addr, e);
-                    return Err(Error::Io(e));
+                    try!(self.send_close(token, true, CloseReason::Error(Phase::Register, e)));
+                    return Ok(Action::None);
                 },
                 _ => {},
             }
@@ -176,10 +540,13 @@ impl Handler {
         debug!("listening on {:?}: {:?}", addr, token);
 
         // stuff it in the hash map
-        self.listeners.insert(token, Listener::new(listener));
+        self.listeners.insert(token, Listener::new(listener, options));
         
         // send response
-        match self.downstream.send(OutputMessage::ListenResponse { listener: token }) {
+        match self.downstream.send(OutputMessage::ListenResponse {
+            listener: token,
+            context:  self.context(&token),
+        }) {
             Err(_) => return Err(Error::DownstreamDisconnect),
             Ok(_)  => {},
         }
@@ -187,246 +554,1762 @@ impl Handler {
         Ok(Action::None)
     }
 
-    fn proc_connect_request (&mut self, eloop: &mut EventLoop, token: Token, addr: net::SocketAddr) -> Result<Action, Error> {
-        let (stream, waiting) = try!(mio::tcp::connect(&addr));
+    fn proc_connect_request (&mut self, eloop: &mut EventLoop, token: Token, addr: net::SocketAddr, options: ConnectOptions, context: Option<UserContext>) -> Result<Action, Error> {
+        if self.token_in_use(&token) {
+            return self.token_collision(token, context);
+        }
+
+        self.set_context(token, context);
+        if options.report_state {
+            self.reporting_state.insert(token);
+        }
+
+        if let Some(policy) = options.reconnect.clone() {
+            self.reconnects.insert(token, Reconnect::new(Target::Addr(addr), options.clone(), policy));
+        }
+
+        self.connect_next(eloop, token, vec![addr], options, None)
+    }
+
+    fn proc_connect_host_request (&mut self, token: Token, host: String, port: u16, options: ConnectOptions, context: Option<UserContext>) -> Result<Action, Error> {
+        if self.token_in_use(&token) {
+            return self.token_collision(token, context);
+        }
+
+        self.set_context(token, context);
+        if options.report_state {
+            self.reporting_state.insert(token);
+        }
+
+        if let Some(policy) = options.reconnect.clone() {
+            self.reconnects.insert(token, Reconnect::new(Target::Host(host.clone(), port), options.clone(), policy));
+        }
+
+        self.resolve(token, host, port, options)
+    }
+
+    fn resolve (&mut self, token: Token, host: String, port: u16, options: ConnectOptions) -> Result<Action, Error> {
+        debug!("resolving {:?}:{:?} for {:?}", host, port, token);
+
+        self.next_request += 1;
+        let request = self.next_request;
+        self.resolving.insert(token, (request, options));
+
+        match self.resolvers.resolve(token, request, host, port) {
+            Err(e) => {
+                error!("failed to start resolving for {:?}: {:?}", token, e);
+                Err(Error::Failed(Phase::Connect, e))
+            },
+            Ok(_) => Ok(Action::None),
+        }
+    }
+
+    fn proc_resolved (&mut self, eloop: &mut EventLoop, token: Token, request: u64, result: Result<Vec<net::SocketAddr>, io::Error>) -> Result<Action, Error> {
+        // the token may have been closed, and even reused, while it was being resolved
+        match self.resolving.get(&token) {
+            Some(&(current, _)) if current == request => {},
+            _ => {
+                debug!("discarding stale resolution {} for {:?}", request, token);
+                return Ok(Action::None);
+            },
+        }
+
+        let options = match self.resolving.remove(&token) {
+            Some((_, options)) => options,
+            None => return Ok(Action::None),
+        };
+
+        match result {
+            Err(e) => {
+                info!("failed to resolve address for {:?}: {:?}", token, e);
+                self.connect_next(eloop, token, Vec::new(), options, Some(CloseReason::Error(Phase::Connect, e)))
+            },
+            Ok(addrs) => {
+                debug!("resolved {:?} to {:?}", token, addrs);
+                self.connect_next(eloop, token, resolver::interleave(addrs), options, None)
+            },
+        }
+    }
+
+    /// start connecting to the first of `candidates` that doesn't fail straight away
+    fn connect_next (&mut self, eloop: &mut EventLoop, token: Token, mut candidates: Vec<net::SocketAddr>, options: ConnectOptions, mut last_error: Option<CloseReason>) -> Result<Action, Error> {
+        while candidates.len() > 0 {
+            let addr = candidates.remove(0);
+
+            debug!("connecting {:?} to {:?}", token, addr);
+
+            let (stream, waiting) = match socket::connect(&addr, &options) {
+                Err(e) => {
+                    info!("failed to connect {:?} to {:?}: {:?}", token, addr, e);
+                    last_error = Some(CloseReason::Error(Phase::Connect, e));
+                    continue;
+                },
+                Ok(x) => x,
+            };
+
+            if !waiting {
+                return self.connected(eloop, token, addr, stream, options, false);
+            }
 
-        if waiting {
             // register the stream for output
             match eloop.register_opt(
                 &stream,
                 token,
                 mio::Interest::writable() | mio::Interest::hup() | mio::Interest::error(),
-                mio::PollOpt::level()
+                poll_opt(options.edge_triggered)
                 ) {
                     Err(e) => {
                         error!("failed to register client at {:?} for writeable: {:?}", &addr, e);
-                        return Err(Error::Io(e));
+                        last_error = Some(CloseReason::Error(Phase::Register, e));
+                        continue;
                     },
                     _ => {},
                 }
 
+            let timeout = match options.attempt_timeout_ms {
+                Some(timeout_ms) => match eloop.timeout_ms(Timeout::ConnectAttempt(token), timeout_ms) {
+                    Err(e) => {
+                        error!("failed to schedule connect timeout for {:?}: {:?}", token, e);
+                        None
+                    },
+                    Ok(timeout) => Some(timeout),
+                },
+                None => None,
+            };
+
             // stuff it in the hash map
-            self.pending_clients.insert(token, (addr, stream));
+            self.pending_clients.insert(token, Pending {
+                addr:       addr,
+                stream:     stream,
+                options:    options,
+                candidates: candidates,
+                timeout:    timeout,
+            });
+
+            return Ok(Action::None);
+        }
 
-            Ok(Action::None)
-        } else {
-            // stick the new client in the hash map
-            try!(new_client(&mut self.clients, eloop, token, addr, stream));
+        // nothing left to try
+        let reason = last_error.unwrap_or_else(|| {
+            CloseReason::Error(Phase::Connect, io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to"))
+        });
+        self.connect_failed(eloop, token, true, reason)
+    }
 
-            Ok(Action::None)
+    /// finish off a connection that couldn't be established or was lost, unless its reconnect
+    /// policy says to try again
+    fn connect_failed (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) -> Result<Action, Error> {
+        if try!(self.schedule_reconnect(eloop, token)) {
+            return Ok(Action::None);
         }
+
+        info!("giving up connecting {:?}", token);
+
+        self.reconnects.remove(&token);
+        try!(self.send_close(token, dirty, reason));
+
+        Ok(Action::None)
     }
 
-    fn proc_data (&mut self, token: Token, data: Vec<u8>) -> Result<Action, Error> {
-        if let Some(&mut (_, ref mut client)) = self.clients.get_mut(&token) {
-            match client.queue_write(&data) {
-                Err(e) => {
-                    error!("error queuing data: {:?}", e);
+    fn schedule_reconnect (&mut self, eloop: &mut EventLoop, token: Token) -> Result<bool, Error> {
+        let (attempt, delay_ms) = match self.reconnects.get_mut(&token) {
+            Some(reconnect) => match reconnect.next_attempt() {
+                Some(delay_ms) => (reconnect.attempt, delay_ms),
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
 
-                    Err(Error::ClientError)
-                },
-                Ok(_) => {
-                    trace!("queued data for {:?}", client.addr);
-                    Ok(Action::TryFlush)
-                },
-            }
-        } else {
-            warn!("received data request for stale token {:?}", token);
-            Ok(Action::None)
+        let timer = match eloop.timeout_ms(Timeout::Reconnect(token), delay_ms) {
+            Err(e) => {
+                error!("failed to schedule reconnect for {:?}: {:?}", token, e);
+                return Ok(false);
+            },
+            Ok(timer) => timer,
+        };
+
+        if let Some(reconnect) = self.reconnects.get_mut(&token) {
+            reconnect.timer = Some(timer);
         }
-    }
 
-    fn proc_stats_request (&mut self, token: Token) -> Result<Action, Error> {
-        if let Some(&(_, ref client)) = self.clients.get(&token) {
-            let stats = client.stats.clone();
-            match self.downstream.send(OutputMessage::StatisticsResponse {
-                token: token,
-                stats: stats,
-            }) {
-                Err(_) => Err(Error::DownstreamDisconnect),
-                Ok(_) => Ok(Action::None),
-            }
-        } else {
-            warn!("received stats request for stale token {:?}", token);
-            Ok(Action::None)
+        info!("reconnecting {:?} in {:?}ms (attempt {:?})", token, delay_ms, attempt);
+
+        match self.downstream.send(OutputMessage::Reconnecting {
+            token:    token,
+            attempt:  attempt,
+            delay_ms: delay_ms,
+            context:  self.context(&token),
+        }) {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_)  => Ok(true),
         }
     }
 
-    fn proc_close (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: Option<io::Error>) -> Result<Action, Error> {
-        if self.clients.contains_key(&token) {
-            if let Some(&mut (_, ref mut client)) = self.clients.get_mut(&token) {
-                match eloop.deregister(client.as_ref()) {
-                    Err(e) => return Err(Error::Io(e)),
-                    _ => {},
-                }
-            }
-
-            self.clients.remove(&token);
-            // the client should be dropped here, causing the TCP close procedure
+    fn reconnect (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
+        let (target, options) = match self.reconnects.get_mut(&token) {
+            Some(reconnect) => {
+                reconnect.timer = None;
+                (reconnect.target.clone(), reconnect.options.clone())
+            },
+            None => return Ok(Action::None),
+        };
 
-            // and finally, notify the downstream
-            match if dirty {
-                self.downstream.send(OutputMessage::DirtyClose { token: token, reason: reason })
-            } else {
-                self.downstream.send(OutputMessage::Close { token: token })
-            } {
-                Err(_) => return Err(Error::DownstreamDisconnect),
-                Ok(_) => {},
-            }
+        match target {
+            Target::Addr(addr) => self.connect_next(eloop, token, vec![addr], options, None),
+            Target::Host(host, port) => self.resolve(token, host, port, options),
         }
+    }
 
-        Ok(Action::None)
+    /// close a connection that failed or hung up, unless its reconnect policy brings it back
+    fn lost (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) {
+        // linked connections go down together, so they're never reconnected
+        let result = if self.reconnects.contains_key(&token) && !self.links.contains_key(&token) && self.clients.contains(&token) {
+            self.reconnect_lost(eloop, token, dirty, reason)
+        } else {
+            self.proc_close(eloop, token, dirty, reason)
+        };
+
+        self.handle_result(eloop, token, result);
     }
 
-    fn deregister_clients (&mut self, eloop: &mut EventLoop) -> Vec<Token> {
-        let mut disconnected_clients = Vec::new();
+    /// tear down a lost connection that has a reconnect policy
+    fn reconnect_lost (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) -> Result<Action, Error> {
+        if let Some(mut connection) = self.clients.remove(&token) {
+            for timeout in connection.take_timers() {
+                eloop.clear_timeout(timeout);
+            }
+            let client = connection.client;
+            info!("lost connection to {:?} on {:?}", client.addr, token);
 
-        for (&token, &mut (_, ref mut client)) in self.clients.iter_mut() {
-            disconnected_clients.push(token);
             match eloop.deregister(client.as_ref()) {
-                Err(_) => {},
-                Ok(_) => {},
+                Err(e) => error!("failed to deregister client at {:?}: {:?}", client.addr, e),
+                _ => {},
             }
         }
 
-        disconnected_clients
-    }
-    fn proc_shutdown (&mut self, eloop: &mut EventLoop) {
-        let disconnected_clients = self.deregister_clients(eloop);
-        self.clients.clear();   // the drop should trigger the TCP close sequence
+        try!(self.connect_failed(eloop, token, dirty, reason));
 
-        for token in disconnected_clients {
-            match self.downstream.send(OutputMessage::Close { token: token }) {
-                Err(_) => {},
-                Ok(_) => {},
-            }
+        // the reconnect policy may have given up, in which case the connection is closed
+        if self.reconnects.contains_key(&token) {
+            try!(self.report_state(token, ConnectionState::Connecting));
         }
 
-        eloop.shutdown();
+        Ok(Action::None)
     }
 
-    fn accept (&mut self, eloop: &mut EventLoop, listener_token: Token) -> Result<(), Error> {
-        if let Some(listener) = self.listeners.get_mut(&listener_token) {
-            match listener.accept() {
-                Err(e) => {
-                    error!("failed to accept incoming connection: {:?}", e);
-                    Err(Error::AcceptFailed)
-                },
-                Ok(None) => Ok(()),
-                Ok(Some(stream)) => {
-                    let addr = match stream.peer_addr() {
-                        Err(e) => {
-                            error!("failed to get peer addr: {:?}", e);
-                            return Err(Error::AcceptFailed);
-                        },
-                        Ok(x) => x,
-                    };
-
-                    let token = self.factory.produce();
+    /// abandon the current attempt of a pending connection, and move on to the next address
+    fn retry_connect (&mut self, eloop: &mut EventLoop, token: Token, reason: CloseReason) -> Result<Action, Error> {
+        if let Some(pending) = self.pending_clients.remove(&token) {
+            if let Some(timeout) = pending.timeout {
+                eloop.clear_timeout(timeout);
+            }
 
-                    // stuff it in the hash map
-                    try!(new_client(&mut self.clients, eloop, token, addr.clone(), stream));
+            info!("failed to connect {:?} to {:?}: {:?}", token, pending.addr, reason);
 
-                    match self.downstream.send(OutputMessage::ConnectRequest {
-                        listener: listener_token,
-                        client:   token,
-                        addr:     addr,
-                    }) {
-                        Err(_) => return Err(Error::DownstreamDisconnect),
-                        Ok(_)  => Ok(()),
-                    }
-                },
+            match eloop.deregister(&pending.stream) {
+                Err(e) => error!("failed to deregister pending client at {:?}: {:?}", pending.addr, e),
+                _ => {},
             }
-        } else {
-            warn!("attempted to accept from stale listener {:?}", listener_token);
 
-            Ok(())
+            self.connect_next(eloop, token, pending.candidates, pending.options, Some(reason))
+        } else {
+            Ok(Action::None)
         }
     }
 
-    fn readable (&mut self, eloop: &mut EventLoop, token: Token, hint: mio::ReadHint) -> Result<Action, Error> {
-        if self.listeners.contains_key(&token) {
-            match self.accept(eloop, token) {
+    fn connected (&mut self, eloop: &mut EventLoop, token: Token, addr: net::SocketAddr, stream: Stream, options: ConnectOptions, registered: bool) -> Result<Action, Error> {
+        let capture = start_capture(&mut self.captures, options.capture.as_ref(), token);
+
+        // a connection that can't be set up counts as a failed attempt
+        let mut client = match outgoing_client(addr, stream, options) {
+            Err(Error::Failed(phase, e)) => return self.connect_failed(eloop, token, true, CloseReason::Error(phase, e)),
+            Err(e) => return Err(e),
+            Ok(client) => client,
+        };
+        client.registered = registered;
+        client.capture = capture;
+
+        let local = client.local;
+
+        // stick the new client in the slab
+        match new_client(&mut self.clients, eloop, token, client) {
+            Err(Error::Failed(phase, e)) => return self.connect_failed(eloop, token, true, CloseReason::Error(phase, e)),
+            Err(e) => return Err(e),
+            Ok(_) => {},
+        }
+
+        if let Some(reconnect) = self.reconnects.get_mut(&token) {
+            reconnect.connected_at = Some(Instant::now());
+        }
+
+        match self.downstream.send(OutputMessage::ConnectResponse {
+            token: token,
+            addr:    addr,
+            local:   local,
+            context: self.context(&token),
+        }) {
+            Err(_) => return Err(Error::DownstreamDisconnect),
+            Ok(_)  => {},
+        }
+
+        try!(self.report_state(token, ConnectionState::Established));
+
+        // flush anything that was queued up front
+        Ok(Action::TryFlush)
+    }
+
+    fn drop_pending (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) -> Result<Action, Error> {
+        self.resolving.remove(&token);
+
+        if let Some(pending) = self.pending_clients.remove(&token) {
+            if let Some(timeout) = pending.timeout {
+                eloop.clear_timeout(timeout);
+            }
+
+            match eloop.deregister(&pending.stream) {
+                Err(e) => error!("failed to deregister pending client at {:?}: {:?}", pending.addr, e),
+                _ => {},
+            }
+        }
+
+        try!(self.send_close(token, dirty, reason));
+
+        Ok(Action::None)
+    }
+
+    fn send_close (&mut self, token: Token, dirty: bool, reason: CloseReason) -> Result<(), Error> {
+        // nothing more is sent for the token, so its context can go, and the token can be reused
+        let context = self.contexts.remove(&token);
+        self.factory.release(token);
+
+        if self.reporting_state.remove(&token) {
+            match self.downstream.send(OutputMessage::StateChanged {
+                token:   token,
+                state:   ConnectionState::Closed,
+                context: context.clone(),
+            }) {
+                Err(_) => return Err(Error::DownstreamDisconnect),
+                Ok(_)  => {},
+            }
+        }
+
+        let result = if dirty {
+            self.downstream.send(OutputMessage::DirtyClose { token: token, reason: reason, context: context })
+        } else {
+            self.downstream.send(OutputMessage::Close { token: token, reason: reason, context: context })
+        };
+        self.downstream.forget(&token);
+
+        match result {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_)  => Ok(()),
+        }
+    }
+
+    fn proc_data (&mut self, token: Token, chunks: Vec<Chunk>) -> Result<Action, Error> {
+        if let Some(&mut Connection { state, ref mut client, .. }) = self.clients.get_mut(&token) {
+            match state {
+                ConnectionState::Established | ConnectionState::HalfClosedRemote => {},
+                _ => {
+                    warn!("discarding data for {:?}, which is {:?}", token, state);
+                    return Ok(Action::None);
+                },
+            }
+
+            for chunk in chunks {
+                client.queue(chunk);
+            }
+
+            trace!("queued data for {:?}", client.addr);
+            Ok(Action::TryFlush)
+        } else {
+            warn!("received data request for stale token {:?}", token);
+            Ok(Action::None)
+        }
+    }
+
+    fn proc_link (&mut self, a: Token, b: Token) -> Result<Action, Error> {
+        if a == b || !self.clients.contains(&a) || !self.clients.contains(&b) {
+            warn!("received link request for invalid tokens {:?} and {:?}", a, b);
+            return Ok(Action::None);
+        }
+
+        if self.links.contains_key(&a) || self.links.contains_key(&b) {
+            warn!("received link request for already linked tokens {:?} and {:?}", a, b);
+            return Ok(Action::None);
+        }
+
+        debug!("linking {:?} and {:?}", a, b);
+
+        // anything already queued on either side still gets flushed as usual
+        self.links.insert(a, Link::new(b));
+        self.links.insert(b, Link::new(a));
+
+        Ok(Action::None)
+    }
+
+    fn proc_stats_request (&mut self, token: Token) -> Result<Action, Error> {
+        let (stats, state) = if let Some(&Connection { state, ref client, .. }) = self.clients.get(&token) {
+            (client.statistics(), state)
+        } else if let Some(state) = self.state(&token) {
+            (Default::default(), state)
+        } else {
+            warn!("received stats request for stale token {:?}", token);
+            return Ok(Action::None);
+        };
+
+        match self.downstream.send(OutputMessage::StatisticsResponse {
+            token:   token,
+            stats:   stats,
+            state:   state,
+            context: self.context(&token),
+        }) {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_) => Ok(Action::None),
+        }
+    }
+
+    fn proc_info_request (&mut self, token: Token) -> Result<Action, Error> {
+        if let Some(&Connection { ref client, .. }) = self.clients.get(&token) {
+            let info = ConnectionInfo {
+                peer:     client.addr,
+                local:    client.local,
+                listener: client.listener,
+                proxy:    client.proxy.clone(),
+                options:  socket::socket_options(client.as_ref()),
+                tcp_info: socket::tcp_info(client.as_ref()),
+            };
+
+            match self.downstream.send(OutputMessage::InfoResponse {
+                token:   token,
+                info:    info,
+                context: self.context(&token),
+            }) {
+                Err(_) => Err(Error::DownstreamDisconnect),
+                Ok(_) => Ok(Action::None),
+            }
+        } else {
+            warn!("received info request for stale token {:?}", token);
+            Ok(Action::None)
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn proc_inject_fault (&mut self, eloop: &mut EventLoop, token: Token, fault: Fault) -> Result<Action, Error> {
+        let (faults, resume_timer) = match self.clients.get_mut(&token) {
+            Some(&mut Connection { ref mut client, ref mut resume_timer, .. }) => (&mut client.faults, resume_timer),
+            None => {
+                warn!("received fault for stale token {:?}", token);
+                return Ok(Action::None);
+            },
+        };
+
+        info!("injecting {:?} into {:?}", fault, token);
+
+        match fault {
+            Fault::DelayReads(ms) => {
+                faults.reads_delayed = true;
+                if let Some(timeout) = resume_timer.take() {
+                    eloop.clear_timeout(timeout);
+                }
+                match eloop.timeout_ms(Timeout::ResumeReads(token), ms) {
+                    Err(e) => error!("failed to schedule resuming reads on {:?}: {:?}", token, e),
+                    Ok(timeout) => *resume_timer = Some(timeout),
+                }
+            },
+            // a cap of 0 would never write anything, while the socket kept saying it's writable
+            Fault::CapWrites(cap) => faults.write_cap = cap.map(|cap| cmp::max(cap, 1)),
+            Fault::FailRead(e) => faults.read_error = Some(e),
+            Fault::FailWrite(e) => {
+                faults.write_error = Some(e);
+                return Ok(Action::TryFlush);
+            },
+
+            Fault::Reset => {
+                let reason = io::Error::new(io::ErrorKind::ConnectionReset, "injected reset");
+                return Err(Error::Failed(Phase::Read, reason));
+            },
+            Fault::Hangup => return Err(Error::ClientDisconnect),
+
+            Fault::Clear => *faults = Default::default(),
+        }
+
+        self.reregister_client(eloop, token).map(|_| Action::None)
+    }
+
+    /// start reading from a connection again once an injected delay is up
+    #[cfg(feature = "fault-injection")]
+    fn resume_reads (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
+        if let Some(&mut Connection { ref mut client, ref mut resume_timer, .. }) = self.clients.get_mut(&token) {
+            client.faults.reads_delayed = false;
+            *resume_timer = None;
+        }
+
+        self.reregister_client(eloop, token).map(|_| Action::None)
+    }
+
+    fn proc_set_rate_limits (&mut self, eloop: &mut EventLoop, token: Token, read: Option<RateLimit>, write: Option<RateLimit>) -> Result<Action, Error> {
+        if let Some(listener) = self.listeners.get_mut(&token) {
+            debug!("updating rate limits for clients of {:?}: {:?}, {:?}", token, read, write);
+            listener.options.read_limit = read;
+            listener.options.write_limit = write;
+            return Ok(Action::None);
+        }
+
+        match self.clients.get_mut(&token) {
+            Some(&mut Connection { write: ref mut write_state, ref mut client, ref mut read_timer, ref mut write_timer, .. }) => {
+                debug!("updating rate limits for {:?}: {:?}, {:?}", token, read, write);
+                client.set_rate_limits(read, write);
+
+                // anything held back by the old limits goes under the new ones
+                for timeout in read_timer.take().into_iter().chain(write_timer.take()) {
+                    eloop.clear_timeout(timeout);
+                }
+                if *write_state == WriteState::Throttled {
+                    *write_state = WriteState::Idle;
+                }
+            },
+            None => {
+                warn!("received rate limits for stale token {:?}", token);
+                return Ok(Action::None);
+            },
+        }
+
+        try!(self.reregister_client(eloop, token));
+        Ok(Action::TryFlush)
+    }
+
+    fn proc_set_priority (&mut self, token: Token, weight: u32) -> Result<Action, Error> {
+        let weight = cmp::max(weight, 1);
+
+        if let Some(listener) = self.listeners.get_mut(&token) {
+            debug!("updating weight for clients of {:?}: {:?}", token, weight);
+            listener.options.weight = weight;
+            return Ok(Action::None);
+        }
+
+        // the new weight applies from the connection's next turn
+        match self.clients.get_mut(&token) {
+            Some(&mut Connection { ref mut client, .. }) => {
+                debug!("updating weight for {:?}: {:?}", token, weight);
+                client.weight = weight;
+            },
+            None => warn!("received weight for stale token {:?}", token),
+        }
+
+        Ok(Action::None)
+    }
+
+    /// start reading from a connection again once its read limit allows
+    fn read_budget_restored (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
+        match self.clients.get_mut(&token) {
+            Some(&mut Connection { ref mut client, ref mut read_timer, .. }) => {
+                *read_timer = None;
+                client.resume_reads();
+            },
+            None => return Ok(Action::None),
+        }
+
+        self.reregister_client(eloop, token).map(|_| Action::None)
+    }
+
+    /// start writing to a connection again once its write limit allows
+    fn write_budget_restored (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
+        match self.clients.get_mut(&token) {
+            // the flush throttles it again if there still isn't enough budget
+            Some(&mut Connection { ref mut write, ref mut write_timer, .. }) => {
+                *write_timer = None;
+                if *write == WriteState::Throttled {
+                    *write = WriteState::Idle;
+                }
+            },
+            None => return Ok(Action::None),
+        }
+
+        Ok(Action::TryFlush)
+    }
+
+    fn proc_set_access_rules (&mut self, token: Token, rules: AccessRules) -> Result<Action, Error> {
+        if let Some(listener) = self.listeners.get_mut(&token) {
+            debug!("updating access rules for {:?}: {:?}", token, rules);
+            listener.options.access = rules;
+        } else {
+            warn!("received access rules for stale listener {:?}", token);
+        }
+
+        Ok(Action::None)
+    }
+
+    fn proc_loop_stats_request (&mut self) -> Result<Action, Error> {
+        match self.downstream.send(OutputMessage::LoopStatisticsResponse {
+            stats: self.stats.clone(),
+        }) {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_) => Ok(Action::None),
+        }
+    }
+
+    fn proc_close (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) -> Result<Action, Error> {
+        if self.listeners.contains_key(&token) {
+            return self.close_listener(eloop, token, dirty, reason);
+        }
+
+        if self.held_clients.contains_key(&token) || self.awaiting_header.contains_key(&token) {
+            return self.drop_held(eloop, token, dirty, reason);
+        }
+
+        // the downstream closing a connection stops it from being reconnected
+        if let Some(reconnect) = self.reconnects.remove(&token) {
+            if let Some(timer) = reconnect.timer {
+                eloop.clear_timeout(timer);
+                return self.drop_pending(eloop, token, dirty, reason);
+            }
+        }
+
+        if self.pending_clients.contains_key(&token) || self.resolving.contains_key(&token) {
+            return self.drop_pending(eloop, token, dirty, reason);
+        }
+
+        // a clean close asked for by the downstream waits for anything still queued to be written,
+        // as does the survivor of a link, whose queue holds what its peer sent before going
+        let draining = match (self.clients.get(&token), &reason) {
+            (Some(connection), &CloseReason::Requested) |
+            (Some(connection), &CloseReason::LinkClosed) => !dirty && connection.client.write_buffered() > 0,
+            _ => false,
+        };
+
+        if draining {
+            if self.state(&token) != Some(ConnectionState::Draining) {
+                debug!("draining {:?} before closing it", token);
+                try!(self.set_state(token, ConnectionState::Draining));
+                try!(self.reregister_client(eloop, token));
+
+                if let Some(connection) = self.clients.get_mut(&token) {
+                    connection.drain_reason = Some(reason);
+                    match eloop.timeout_ms(Timeout::Drain(token), connection.client.drain_timeout_ms) {
+                        Err(e) => error!("failed to schedule drain timeout for {:?}: {:?}", token, e),
+                        Ok(timeout) => connection.drain_timer = Some(timeout),
+                    }
+                }
+            }
+
+            return Ok(Action::None);
+        }
+
+        if let Some(mut connection) = self.clients.remove(&token) {
+            for timeout in connection.take_timers() {
+                eloop.clear_timeout(timeout);
+            }
+            let client = connection.client;
+
+            match eloop.deregister(client.as_ref()) {
+                Err(e) => error!("failed to deregister client at {:?}: {:?}", client.addr, e),
+                _ => {},
+            }
+
+            if let Some(listener) = client.listener {
+                try!(self.release_client(eloop, listener, &client.addr));
+            }
+            // the client should be dropped here, causing the TCP close procedure
+
+            // and finally, notify the downstream
+            try!(self.send_close(token, dirty, reason));
+
+            // closing either side of a link closes the other as well
+            if let Some(link) = self.links.remove(&token) {
+                self.links.remove(&link.peer);
+                try!(self.proc_close(eloop, link.peer, false, CloseReason::LinkClosed));
+            }
+        }
+
+        Ok(Action::None)
+    }
+
+    /// stop listening; the clients the listener accepted carry on without it
+    fn close_listener (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) -> Result<Action, Error> {
+        let mut listener = match self.listeners.remove(&token) {
+            Some(listener) => listener,
+            None => return Ok(Action::None),
+        };
+
+        // the token may go to a new listener, which these mustn't fire on
+        for timeout in listener.resume_timer.take().into_iter().chain(listener.accept_timer.take()) {
+            eloop.clear_timeout(timeout);
+        }
+
+        match eloop.deregister(&*listener) {
+            Err(e) => error!("failed to deregister listener {:?}: {:?}", token, e),
+            _ => {},
+        }
+        // the socket is dropped here, so nothing more is accepted
+
+        // the token may be reused for another listener, whose limits these clients mustn't count
+        // against
+        for connection in self.clients.iter_mut() {
+            if connection.client.listener == Some(token) {
+                connection.client.listener = None;
+            }
+        }
+
+        for &mut (ref mut client, _) in self.held_clients.values_mut() {
+            if client.listener == Some(token) {
+                client.listener = None;
+            }
+        }
+
+        // clients still sending their PROXY header haven't been announced, and now can't be
+        let unannounced: Vec<Token> = self.awaiting_header.iter()
+            .filter(|&(_, &(ref client, _, _))| client.listener == Some(token))
+            .map(|(client_token, _)| *client_token)
+            .collect();
+
+        for client_token in unannounced {
+            let reason = io::Error::new(io::ErrorKind::ConnectionAborted, "listener closed");
+            let result = self.drop_held(eloop, client_token, true, CloseReason::Error(Phase::Policy, reason));
+            self.handle_result(eloop, client_token, result);
+        }
+
+        try!(self.send_close(token, dirty, reason));
+
+        Ok(Action::None)
+    }
+
+    fn deregister_clients (&mut self, eloop: &mut EventLoop) -> Vec<Token> {
+        let mut disconnected_clients = Vec::new();
+
+        for connection in self.clients.iter_mut() {
+            disconnected_clients.push(connection.token);
+            match eloop.deregister(connection.client.as_ref()) {
+                Err(_) => {},
+                Ok(_) => {},
+            }
+        }
+
+        disconnected_clients
+    }
+    fn proc_shutdown (&mut self, eloop: &mut EventLoop) {
+        let mut disconnected_clients = self.deregister_clients(eloop);
+        self.clients.clear();   // the drop should trigger the TCP close sequence
+
+        for (token, (client, _)) in self.held_clients.drain() {
+            disconnected_clients.push(token);
+            match eloop.deregister(client.as_ref()) {
+                Err(_) => {},
+                Ok(_) => {},
+            }
+        }
+
+        for (token, (client, _, _)) in self.awaiting_header.drain() {
+            disconnected_clients.push(token);
+            match eloop.deregister(client.as_ref()) {
+                Err(_) => {},
+                Ok(_) => {},
+            }
+        }
+
+        for (token, pending) in self.pending_clients.drain() {
+            disconnected_clients.push(token);
+            match eloop.deregister(&pending.stream) {
+                Err(_) => {},
+                Ok(_) => {},
+            }
+        }
+
+        for (token, _) in self.resolving.drain() {
+            disconnected_clients.push(token);
+        }
+
+        // connections waiting to reconnect aren't anywhere else
+        for (token, reconnect) in self.reconnects.drain() {
+            if reconnect.timer.is_some() {
+                disconnected_clients.push(token);
+            }
+        }
+        self.links.clear();
+        self.run_queue.clear();
+
+        for token in disconnected_clients {
+            match self.send_close(token, false, CloseReason::Shutdown) {
+                Err(_) => {},
+                Ok(_) => {},
+            }
+        }
+
+        eloop.shutdown();
+    }
+
+    fn set_accepting (&mut self, eloop: &mut EventLoop, token: Token, accepting: bool) -> Result<(), Error> {
+        if let Some(listener) = self.listeners.get_mut(&token) {
+            if listener.accepting == accepting {
+                return Ok(());
+            }
+
+            let interest = if accepting {
+                mio::Interest::readable() | mio::Interest::hup() | mio::Interest::error()
+            } else {
+                mio::Interest::error()
+            };
+
+            match eloop.reregister(
+                &**listener,
+                token,
+                interest,
+                poll_opt(listener.options.edge_triggered)
+                ) {
+                    Err(e) => {
+                        error!("failed to reregister listener {:?}: {:?}", token, e);
+                        return Err(Error::Failed(Phase::Register, e));
+                    },
+                    _ => {},
+                }
+
+            listener.accepting = accepting;
+        }
+
+        Ok(())
+    }
+
+    fn pause_accepting (&mut self, eloop: &mut EventLoop, listener_token: Token, reason: RejectReason) -> Result<(), Error> {
+        info!("pausing accepts on {:?}: {:?}", listener_token, reason);
+
+        try!(self.set_accepting(eloop, listener_token, false));
+
+        // the client limit lifts as clients close, but the accept rate needs a timer
+        if reason == RejectReason::AcceptRate {
+            let delay = self.listeners.get_mut(&listener_token).and_then(|listener| listener.accept_delay_ms()).unwrap_or(0);
+
+            try!(self.resume_accepting_after(eloop, listener_token, delay));
+        }
+
+        Ok(())
+    }
+
+    /// stop accepting for a while after running out of descriptors; the connection that couldn't
+    /// be accepted stays in the backlog, and the timer brings the listener back to it
+    fn back_off_accepting (&mut self, eloop: &mut EventLoop, listener_token: Token) -> Result<(), Error> {
+        let delay = match self.listeners.get_mut(&listener_token) {
+            Some(listener) => listener.back_off(),
+            None => return Ok(()),
+        };
+
+        warn!("out of descriptors, pausing accepts on {:?} for {}ms", listener_token, delay);
+
+        try!(self.set_accepting(eloop, listener_token, false));
+
+        self.resume_accepting_after(eloop, listener_token, delay)
+    }
+
+    /// schedule a paused listener to start accepting again, in place of any timer already set
+    fn resume_accepting_after (&mut self, eloop: &mut EventLoop, listener_token: Token, delay: u64) -> Result<(), Error> {
+        let scheduled = match self.listeners.get_mut(&listener_token) {
+            Some(listener) => {
+                if let Some(timeout) = listener.resume_timer.take() {
+                    eloop.clear_timeout(timeout);
+                }
+
+                match eloop.timeout_ms(Timeout::ResumeAccept(listener_token), delay) {
+                    Err(e) => {
+                        error!("failed to schedule resuming accepts on {:?}: {:?}", listener_token, e);
+                        false
+                    },
+                    Ok(timeout) => {
+                        listener.resume_timer = Some(timeout);
+                        true
+                    },
+                }
+            },
+            None => return Ok(()),
+        };
+
+        if !scheduled {
+            try!(self.set_accepting(eloop, listener_token, true));
+        }
+
+        Ok(())
+    }
+
+    fn release_client (&mut self, eloop: &mut EventLoop, listener_token: Token, addr: &net::SocketAddr) -> Result<(), Error> {
+        let resume = match self.listeners.get_mut(&listener_token) {
+            Some(listener) => {
+                listener.release(addr);
+                !listener.accepting && listener.check_limits().is_none()
+            },
+            None => false,
+        };
+
+        if resume {
+            info!("resuming accepts on {:?}", listener_token);
+            try!(self.set_accepting(eloop, listener_token, true));
+        }
+
+        Ok(())
+    }
+
+    fn hold_client (&mut self, eloop: &mut EventLoop, token: Token, mut client: Client, timeout_ms: u64) -> Result<(), Error> {
+        info!("holding new client at {:?} for a decision", client.addr);
+
+        // nothing is read until the client is accepted, but hangups are still noticed
+        try!(register_client(eloop, token, &mut client, mio::Interest::hup() | mio::Interest::error()));
+
+        let timeout = match eloop.timeout_ms(Timeout::AcceptDecision(token), timeout_ms) {
+            Err(e) => {
+                error!("failed to schedule accept decision timeout for {:?}: {:?}", token, e);
+                None
+            },
+            Ok(timeout) => Some(timeout),
+        };
+
+        self.held_clients.insert(token, (client, timeout));
+
+        Ok(())
+    }
+
+    fn proc_accept (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
+        if let Some((mut client, timeout)) = self.held_clients.remove(&token) {
+            if let Some(timeout) = timeout {
+                eloop.clear_timeout(timeout);
+            }
+
+            debug!("accepted held client {:?} at {:?}", token, client.addr);
+
+            let read_ahead = mem::replace(&mut client.read_ahead, Vec::new());
+
+            // from here on its events come in under its slab key; if that fails, it's still
+            // recorded so that closing it cleans up
+            let (listener, addr) = (client.listener, client.addr);
+            let key = match self.clients.insert(token, client) {
+                Ok(key) => key,
+                Err(e) => {
+                    error!("no room for accepted client {:?}: {:?}", token, e);
+                    if let Some(listener) = listener {
+                        try!(self.release_client(eloop, listener, &addr));
+                    }
+                    try!(self.send_close(token, true, CloseReason::Error(Phase::Register, e)));
+                    return Ok(Action::None);
+                },
+            };
+            if let Some(&mut Connection { ref client, .. }) = self.clients.by_key(key) {
+                match eloop.reregister(
+                    client.as_ref(),
+                    key.token(),
+                    client_interest(ConnectionState::Established, WriteState::Idle, None),
+                    poll_opt(client.edge)
+                    ) {
+                        Err(e) => {
+                            error!("failed to reregister accepted client at {:?}: {:?}", client.addr, e);
+                            return Err(Error::Failed(Phase::Register, e));
+                        },
+                        _ => {},
+                    }
+            }
+
+            try!(self.report_state(token, ConnectionState::Established));
+
+            try!(self.send_read_ahead(token, read_ahead));
+        } else {
+            warn!("received accept for stale token {:?}", token);
+        }
+
+        Ok(Action::None)
+    }
+
+    fn send_read_ahead (&mut self, token: Token, data: Vec<u8>) -> Result<(), Error> {
+        if data.len() == 0 {
+            return Ok(());
+        }
+
+        match self.downstream.send(OutputMessage::Data {
+            token:   token,
+            data:    data,
+            context: self.context(&token),
+        }) {
+            Err(_) => Err(Error::DownstreamDisconnect),
+            Ok(_)  => Ok(()),
+        }
+    }
+
+    fn drop_held (&mut self, eloop: &mut EventLoop, token: Token, dirty: bool, reason: CloseReason) -> Result<Action, Error> {
+        let client = if let Some((client, timeout)) = self.held_clients.remove(&token) {
+            if let Some(timeout) = timeout {
+                eloop.clear_timeout(timeout);
+            }
+
+            Some(client)
+        } else if let Some((client, _, timeout)) = self.awaiting_header.remove(&token) {
+            if let Some(timeout) = timeout {
+                eloop.clear_timeout(timeout);
+            }
+
+            Some(client)
+        } else {
+            None
+        };
+
+        if let Some(client) = client {
+            match eloop.deregister(client.as_ref()) {
+                Err(e) => error!("failed to deregister held client at {:?}: {:?}", client.addr, e),
+                _ => {},
+            }
+
+            if let Some(listener) = client.listener {
+                try!(self.release_client(eloop, listener, &client.addr));
+            }
+            // the client is dropped here, closing the connection
+
+            try!(self.send_close(token, dirty, reason));
+        } else {
+            warn!("received reject for stale token {:?}", token);
+        }
+
+        Ok(Action::None)
+    }
+
+    fn proc_reject (&mut self, eloop: &mut EventLoop, token: Token, reason: String) -> Result<Action, Error> {
+        info!("downstream rejected held client {:?}: {}", token, reason);
+
+        let reason = io::Error::new(io::ErrorKind::ConnectionRefused, reason);
+        self.drop_held(eloop, token, true, CloseReason::Error(Phase::Policy, reason))
+    }
+
+    fn accept (&mut self, eloop: &mut EventLoop, listener_token: Token) -> Result<(), Error> {
+        let (batch, edge) = match self.listeners.get(&listener_token) {
+            Some(listener) => (cmp::max(listener.options.accept_batch, 1), listener.options.edge_triggered),
+            None => {
+                warn!("attempted to accept from stale listener {:?}", listener_token);
+                return Ok(());
+            },
+        };
+
+        for _ in 0..batch {
+            match self.accept_one(eloop, listener_token) {
+                Ok(true) => {},
+                Ok(false) => return Ok(()),
+
+                // that connection is lost, but the rest of the backlog is still there; carry on
+                // unless accepting has backed off, when the timer picks it up again
+                Err(Error::AcceptFailed) => {
+                    if !self.listeners.get(&listener_token).map(|listener| listener.accepting).unwrap_or(false) {
+                        return Ok(());
+                    }
+                },
+
+                Err(e) => return Err(e),
+            }
+        }
+
+        // there may be more in the backlog, but an edge-triggered listener won't be told again
+        if edge {
+            if let Some(listener) = self.listeners.get_mut(&listener_token) {
+                if let Some(timeout) = listener.accept_timer.take() {
+                    eloop.clear_timeout(timeout);
+                }
+                match eloop.timeout_ms(Timeout::Accept(listener_token), 0) {
+                    Err(e) => error!("failed to schedule draining listener {:?}: {:?}", listener_token, e),
+                    Ok(timeout) => listener.accept_timer = Some(timeout),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// accept a single connection, returning whether one was taken off the backlog
+    fn accept_one (&mut self, eloop: &mut EventLoop, listener_token: Token) -> Result<bool, Error> {
+        let limited = match self.listeners.get_mut(&listener_token) {
+            Some(listener) => listener.check_limits().map(|reason| (reason, listener.options.over_limit)),
+            None => return Ok(false),
+        };
+
+        if let Some((reason, OverLimit::Pause)) = limited {
+            try!(self.pause_accepting(eloop, listener_token, reason));
+            return Ok(false);
+        }
+
+        let accepted = match self.listeners.get_mut(&listener_token) {
+            Some(listener) => listener.accept(),
+            None => return Ok(false),
+        };
+
+        let stream = match accepted {
+            Err(e) => {
+                error!("failed to accept incoming connection: {:?}", e);
+
+                if socket::out_of_descriptors(&e) {
+                    try!(self.back_off_accepting(eloop, listener_token));
+                }
+
+                return Err(Error::AcceptFailed);
+            },
+            Ok(None) => return Ok(false),
+            Ok(Some(stream)) => stream,
+        };
+
+        let (addr, rejected, proxied, edge) = match self.listeners.get_mut(&listener_token) {
+            Some(listener) => {
+                listener.accepted();
+
+                let addr = match stream.peer_addr() {
+                    Err(e) => {
+                        error!("failed to get peer addr: {:?}", e);
+                        return Err(Error::AcceptFailed);
+                    },
+                    Ok(x) => x,
+                };
+
+                // anything still over a limit here is turned away, but access rules come first
+                let rejected = match limited {
+                    Some((reason, _)) if listener.options.access.permits(&addr.ip()) => Some(reason),
+                    _ => listener.admit(&addr),
+                };
+
+                (addr, rejected, listener.options.proxy_protocol, listener.options.edge_triggered)
+            },
+            None => return Ok(false),
+        };
+
+        if let Some(reason) = rejected {
+            info!("rejecting connection from {:?} on {:?}: {:?}", addr, listener_token, reason);
+
+            if reason == RejectReason::AccessDenied {
+                self.stats.connections_denied += 1;
+            } else {
+                self.stats.connections_rejected += 1;
+            }
+
+            // dropping the stream closes the connection
+            drop(stream);
+
+            return match self.downstream.send(OutputMessage::ConnectionRejected {
+                listener: listener_token,
+                addr:     addr,
+                reason:   reason,
+                context:  self.context(&listener_token),
+            }) {
+                Err(_) => Err(Error::DownstreamDisconnect),
+                Ok(_)  => Ok(true),
+            };
+        }
+
+        let token = self.factory.produce();
+
+        if self.token_in_use(&token) {
+            error!("token factory produced {:?}, which is already in use", token);
+            self.stats.connections_rejected += 1;
+
+            if let Some(listener) = self.listeners.get_mut(&listener_token) {
+                listener.release(&addr);
+            }
+            drop(stream);
+
+            return match self.downstream.send(OutputMessage::ConnectionRejected {
+                listener: listener_token,
+                addr:     addr,
+                reason:   RejectReason::TokenInUse,
+                context:  self.context(&listener_token),
+            }) {
+                Err(_) => Err(Error::DownstreamDisconnect),
+                Ok(_)  => Ok(true),
+            };
+        }
+
+        // accepted clients start out with their listener's context and settings
+        if let Some(context) = self.context(&listener_token) {
+            self.contexts.insert(token, context);
+        }
+        if self.listeners.get(&listener_token).map(|listener| listener.options.report_state).unwrap_or(false) {
+            self.reporting_state.insert(token);
+        }
+
+        let mut client = Client::new(addr, stream);
+        client.listener = Some(listener_token);
+        client.edge = edge;
+        if let Some(listener) = self.listeners.get(&listener_token) {
+            client.capture = start_capture(&mut self.captures, listener.options.capture.as_ref(), token);
+            client.set_rate_limits(listener.options.read_limit, listener.options.write_limit);
+            client.weight = cmp::max(listener.options.weight, 1);
+            client.drain_timeout_ms = listener.options.drain_timeout_ms;
+        }
+
+        if proxied {
+            // the client isn't announced until its PROXY header says who it really is
+            match register_client(eloop, token, &mut client, client_interest(ConnectionState::Established, WriteState::Idle, None)) {
+                Err(_) => {
+                    if let Some(listener) = self.listeners.get_mut(&listener_token) {
+                        listener.release(&addr);
+                    }
+                    self.contexts.remove(&token);
+                    self.reporting_state.remove(&token);
+                    return Err(Error::AcceptFailed);
+                },
+                Ok(_) => {},
+            }
+
+            let timeout_ms = self.listeners.get(&listener_token).map(|listener| listener.options.header_timeout_ms).unwrap_or(0);
+            let timeout = match eloop.timeout_ms(Timeout::ProxyHeader(token), timeout_ms) {
+                Err(e) => {
+                    error!("failed to schedule PROXY header timeout for {:?}: {:?}", token, e);
+                    None
+                },
+                Ok(timeout) => Some(timeout),
+            };
+
+            self.awaiting_header.insert(token, (client, Vec::new(), timeout));
+
+            return Ok(true);
+        }
+
+        try!(self.announce_client(eloop, token, listener_token, client, None));
+
+        Ok(true)
+    }
+
+    fn announce_client (&mut self, eloop: &mut EventLoop, token: Token, listener_token: Token, mut client: Client, proxy: Option<ProxyHeader>) -> Result<(), Error> {
+        let addr = client.addr;
+        let local = client.local;
+        client.proxy = proxy.clone();
+
+        let hold = match self.listeners.get(&listener_token) {
+            Some(listener) if listener.options.manual_accept => Some(listener.options.accept_timeout_ms),
+            _ => None,
+        };
+
+        // a held client keeps anything read ahead until it's accepted
+        let read_ahead = match hold {
+            Some(_) => Vec::new(),
+            None    => mem::replace(&mut client.read_ahead, Vec::new()),
+        };
+
+        // stuff it in the hash map, or hold it until the downstream decides what to do with it
+        let result = match hold {
+            Some(timeout_ms) => self.hold_client(eloop, token, client, timeout_ms),
+            None => new_client(&mut self.clients, eloop, token, client),
+        };
+
+        match result {
+            Err(_) => {
+                if let Some(listener) = self.listeners.get_mut(&listener_token) {
+                    listener.release(&addr);
+                }
+                self.contexts.remove(&token);
+                self.reporting_state.remove(&token);
+                return Err(Error::AcceptFailed);
+            },
+            Ok(_) => {},
+        }
+
+        self.stats.connections_accepted += 1;
+
+        match self.downstream.send(OutputMessage::ConnectRequest {
+            listener: listener_token,
+            client:   token,
+            addr:     addr,
+            local:    local,
+            proxy:    proxy,
+            context:  self.context(&token),
+        }) {
+            Err(_) => return Err(Error::DownstreamDisconnect),
+            Ok(_)  => {},
+        }
+
+        self.send_read_ahead(token, read_ahead)
+    }
+
+    fn read_proxy_header (&mut self, eloop: &mut EventLoop, token: Token, hint: mio::ReadHint) -> Result<Action, Error> {
+        let header = match self.awaiting_header.get_mut(&token) {
+            Some(&mut (ref mut client, ref mut buffer, _)) => {
+                if hint.contains(mio::ReadHint::error()) {
+                    info!("error from client {:?}", client.addr);
+                    return Err(Error::Failed(Phase::Read, socket::take_error(client.as_ref())));
+                }
+
+                match client.try_read_all(usize::max_value()) {
+                    Err(e) => {
+                        info!("error reading PROXY header from client at {:?}: {:?}", client.addr, e);
+                        return Err(Error::Failed(Phase::Read, e));
+                    },
+                    Ok(None) => {},
+                    Ok(Some(data)) => buffer.extend(data),
+                }
+
+                match proxy::parse(&buffer[..]) {
+                    Err(_) => {
+                        info!("malformed PROXY header from client at {:?}", client.addr);
+                        return Err(Error::Failed(Phase::Codec, io::Error::new(io::ErrorKind::InvalidData, "malformed PROXY protocol header")));
+                    },
+
+                    // not all here yet
+                    Ok(None) => {
+                        if hint.contains(mio::ReadHint::hup()) {
+                            return Err(Error::ClientDisconnect);
+                        }
+
+                        return Ok(Action::None);
+                    },
+
+                    // anything after the header is the start of the proxied stream
+                    Ok(Some((header, length))) => {
+                        client.read_ahead = buffer[length..].to_vec();
+                        header
+                    },
+                }
+            },
+            None => return Ok(Action::None),
+        };
+
+        if let Some((client, _, timeout)) = self.awaiting_header.remove(&token) {
+            if let Some(timeout) = timeout {
+                eloop.clear_timeout(timeout);
+            }
+
+            debug!("PROXY header from client at {:?}: {:?}", client.addr, header);
+
+            let listener_token = client.listener.unwrap_or(token);
+            try!(self.announce_client(eloop, token, listener_token, client, Some(header)));
+        }
+
+        Ok(Action::None)
+    }
+
+    fn readable (&mut self, eloop: &mut EventLoop, token: Token, hint: mio::ReadHint) -> Result<Action, Error> {
+        if self.listeners.contains_key(&token) {
+            match self.accept(eloop, token) {
                 Err(e) => Err(e),
                 Ok(_) => Ok(Action::None),
             }
+        } else if let Some(error) = self.pending_clients.get(&token).map(|pending| socket::take_error(&pending.stream)) {
+            // pending clients are only readable when the connect failed
+            self.retry_connect(eloop, token, CloseReason::Error(Phase::Connect, error))
+        } else if self.awaiting_header.contains_key(&token) {
+            self.read_proxy_header(eloop, token, hint)
+        } else if let Some(error) = self.held_clients.get(&token).map(|&(ref client, _)| socket::take_error(client.as_ref())) {
+            // held clients are only registered for hangups and errors
+            info!("held client {:?} disconnected", token);
+
+            if hint.contains(mio::ReadHint::error()) {
+                self.drop_held(eloop, token, true, CloseReason::Error(Phase::Read, error))
+            } else {
+                self.drop_held(eloop, token, false, CloseReason::Hangup)
+            }
         } else {
-            if let Some(&mut (_, ref mut client)) = self.clients.get_mut(&token) {
-                if hint.contains(mio::ReadHint::error()) {
-                    // client read error
-                    info!("error from client {:?}", client.addr);
-                    return Err(Error::ClientError);
-                }
+            warn!("received readable event for stale token {:?}", token);
+            Ok(Action::None)
+        }
+    }
 
-                debug!("reading from {:?} at {:?}", token, client.addr);
+    /// handle a readable event for an established client, found by the key it's registered under
+    ///
+    /// At most `max` bytes are read; the flag returned says whether there may be more to read.
+    fn client_readable (&mut self, eloop: &mut EventLoop, key: Key, hint: mio::ReadHint, max: usize) -> (Token, Result<Action, Error>, bool) {
+        // a linked client reads no more than its peer has room to queue
+        let max = match self.link_room(key) {
+            Some((token, 0)) if !hint.contains(mio::ReadHint::error()) => {
+                return (token, self.pause_link(eloop, token).map(|_| Action::None), false);
+            },
+            Some((_, room)) => cmp::min(max, room),
+            None => max,
+        };
 
-                // try to read some data
-                match client.try_read_all() {
-                    Err(e) => {
-                        info!("error reading data from client at {:?}: {:?}", client.addr, e);
-                        return Err(Error::ClientError);
-                    },
+        let (token, addr, data, throttled) = if let Some(&mut Connection { token, ref mut client, .. }) = self.clients.by_key(key) {
+            if hint.contains(mio::ReadHint::error()) {
+                // client read error
+                info!("error from client {:?}", client.addr);
+                return (token, Err(Error::Failed(Phase::Read, socket::take_error(client.as_ref()))), false);
+            }
 
-                    // would block
-                    Ok(None) => {},
+            debug!("reading from {:?} at {:?}", token, client.addr);
 
-                    // we got data! if there's no bytes we don't send a message to the downstream,
-                    // since we'll separately send a client disconnect (a zero length read is
-                    // typically indicative of a hangup, which we check for later)
-                    Ok(Some(data)) => if data.len() > 0 {
-                        // kick the packets over to the downstream
-                        match self.downstream.send(OutputMessage::Data {
-                            token: token,
-                            data:  data,
-                        }) {
-                            Err(_) => {
-                                error!("downstream disconnected");
-                                return Err(Error::DownstreamDisconnect);
-                            },
-                            _ => {},
-                        }
-                    },
-                }
+            // try to read some data
+            let data = match client.try_read_all(max) {
+                Err(e) => {
+                    info!("error reading data from client at {:?}: {:?}", client.addr, e);
+                    return (token, Err(Error::Failed(Phase::Read, e)), false);
+                },
 
-                if hint.contains(mio::ReadHint::hup()) {
-                    // client hung up
-                    info!("client at {:?} disconnected", client.addr);
-                    return Err(Error::ClientDisconnect);
+                // would block
+                Ok(None) => Vec::new(),
+
+                Ok(Some(data)) => data,
+            };
+
+            (token, client.addr, data, client.throttle_reads())
+        } else {
+            // the client was closed after this event was queued, and its slot may have been reused
+            warn!("received readable event for stale key {:?}", key);
+
+            return (key.token(), Ok(Action::None), false);
+        };
+
+        // a full read may have left data behind, so the connection needs another turn
+        let more = data.len() >= max && throttled.is_none();
+
+        // out of read budget, so stop reading until there's more
+        if let Some(delay) = throttled {
+            debug!("throttling reads from {:?} for {:?}", token, delay);
+            if let Some(connection) = self.clients.by_key(key) {
+                if let Some(timeout) = connection.read_timer.take() {
+                    eloop.clear_timeout(timeout);
+                }
+                match eloop.timeout_ms(Timeout::ReadBudget(token), cmp::max(throttle::millis(delay), 1)) {
+                    Err(e) => error!("failed to schedule resuming reads from {:?}: {:?}", token, e),
+                    Ok(timeout) => connection.read_timer = Some(timeout),
                 }
+            }
+
+            if let Err(e) = self.reregister_client(eloop, token) {
+                return (token, Err(e), false);
+            }
+        }
+
+        // a throttled client, or one that used up its turn, may have data left to read before the
+        // hangup
+        let hup = hint.contains(mio::ReadHint::hup()) && throttled.is_none() && !more;
+
+        // linked clients hand their data straight to their peer
+        if let Some(peer) = self.links.get(&token).map(|link| link.peer) {
+            return (token, self.forward(eloop, token, peer, data, hup), more);
+        }
+
+        // we got data! if there's no bytes we don't send a message to the downstream,
+        // since we'll separately send a client disconnect (a zero length read is
+        // typically indicative of a hangup, which we check for later)
+        if data.len() > 0 {
+            // kick the packets over to the downstream
+            match self.downstream.send(OutputMessage::Data {
+                token:   token,
+                data:    data,
+                context: self.context(&token),
+            }) {
+                Err(_) => {
+                    error!("downstream disconnected");
+                    return (token, Err(Error::DownstreamDisconnect), false);
+                },
+                _ => {},
+            }
+        }
+
+        if hup {
+            // client hung up
+            info!("client at {:?} disconnected", addr);
+            return (token, Err(Error::ClientDisconnect), false);
+        }
+
+        (token, Ok(Action::None), more)
+    }
+
+    fn forward (&mut self, eloop: &mut EventLoop, token: Token, peer: Token, data: Vec<u8>, hup: bool) -> Result<Action, Error> {
+        if data.len() > 0 {
+            if let Some(&mut Connection { ref mut client, .. }) = self.clients.get_mut(&peer) {
+                trace!("forwarding {:?} bytes from {:?} to {:?}", data.len(), token, peer);
+                client.queue(Chunk::Owned(data));
+            }
+
+            self.queue_flush(eloop, peer);
+
+            // couple the two sides: stop reading here until the peer catches up
+            let backlog = self.clients.get(&peer).map(|connection| connection.client.write_buffered()).unwrap_or(0);
+            if backlog >= LINK_HIGH_WATER {
+                debug!("{:?} has {:?} bytes queued", peer, backlog);
+                try!(self.pause_link(eloop, token));
+            }
+        }
+
+        if hup {
+            // propagate the half-close: the peer's write half is shut down once it has flushed
+            info!("linked client {:?} hung up", token);
 
-                Ok(Action::None)
+            if let Some(link) = self.links.get_mut(&token) {
+                link.read_closed = true;
+            }
+            try!(self.half_close(token, true));
+            try!(self.reregister_client(eloop, token));
+
+            let result = self.close_write_if_drained(peer);
+            self.handle_result(eloop, peer, result);
+        }
+
+        Ok(Action::None)
+    }
+
+    /// a linked client's token, and how much more its peer can queue before it's at
+    /// LINK_HIGH_WATER
+    fn link_room (&mut self, key: Key) -> Option<(Token, usize)> {
+        let token = match self.clients.by_key(key) {
+            Some(&mut Connection { token, .. }) => token,
+            None => return None,
+        };
+
+        let peer = match self.links.get(&token) {
+            Some(link) => link.peer,
+            None => return None,
+        };
+
+        let backlog = self.clients.get(&peer).map(|connection| connection.client.write_buffered()).unwrap_or(0);
+        Some((token, LINK_HIGH_WATER.saturating_sub(backlog)))
+    }
+
+    /// stop reading from a linked client until its peer's queue drains below LINK_LOW_WATER
+    fn pause_link (&mut self, eloop: &mut EventLoop, token: Token) -> Result<(), Error> {
+        match self.links.get_mut(&token) {
+            Some(link) => if link.paused {
+                return Ok(());
+            } else {
+                link.paused = true;
+            },
+            None => return Ok(()),
+        }
+
+        debug!("pausing reads on {:?} until its peer catches up", token);
+        self.reregister_client(eloop, token)
+    }
+
+    fn resume_peer (&mut self, eloop: &mut EventLoop, token: Token) {
+        let peer = match self.links.get(&token) {
+            Some(link) => link.peer,
+            None       => return,
+        };
+
+        match self.links.get_mut(&peer) {
+            Some(link) => if link.paused {
+                link.paused = false;
             } else {
-                warn!("received readable event for stale token {:?}", token);
+                return;
+            },
+            None => return,
+        }
 
-                Ok(Action::None)
+        debug!("resuming reads on {:?}", peer);
+
+        let result = self.reregister_client(eloop, peer).map(|_| Action::None);
+        self.handle_result(eloop, peer, result);
+    }
+
+    fn close_write_if_drained (&mut self, token: Token) -> Result<Action, Error> {
+        let peer = match self.links.get(&token) {
+            Some(link) if !link.write_closed => link.peer,
+            _ => return Ok(Action::None),
+        };
+
+        let peer_write_closed = match self.links.get(&peer) {
+            Some(link) if link.read_closed => link.write_closed,
+            _ => return Ok(Action::None),
+        };
+
+        if let Some(&mut Connection { ref mut client, .. }) = self.clients.get_mut(&token) {
+            if client.write_buffered() > 0 {
+                return Ok(Action::None);
             }
+
+            debug!("peer of {:?} hung up, shutting down write half", token);
+            try!(client.shutdown_write().map_err(|e| Error::Failed(Phase::Write, e)));
+        }
+
+        if let Some(link) = self.links.get_mut(&token) {
+            link.write_closed = true;
+        }
+        try!(self.half_close(token, false));
+
+        // both sides have hung up and been flushed, so the link is finished
+        if peer_write_closed {
+            Err(Error::ClientDisconnect)
+        } else {
+            Ok(Action::None)
         }
     }
 
     fn writable (&mut self, eloop: &mut EventLoop, token: Token) -> Result<Action, Error> {
-        if self.clients.contains_key(&token) {
-            if let Some(&mut (ref mut waiting_for_write, _)) = self.clients.get_mut(&token) {
-                if !*waiting_for_write {
-                    error!("received writable event, but not waiting for write on token {:?}!", token);
-                    return Ok(Action::None);
-                }
+        if let Some(pending) = self.pending_clients.remove(&token) {
+            if let Some(timeout) = pending.timeout {
+                eloop.clear_timeout(timeout);
+            }
 
-                // now we fall through to the flush
+            // a failed connect shows up as writable too, but won't have a peer
+            if pending.stream.peer_addr().is_err() {
+                let reason = CloseReason::Error(Phase::Connect, socket::take_error(&pending.stream));
+                self.pending_clients.insert(token, Pending { timeout: None, .. pending });
+                return self.retry_connect(eloop, token, reason);
             }
 
-            self.try_flush(eloop, token)
+            self.connected(eloop, token, pending.addr, pending.stream, pending.options, true)
         } else {
-            if let Some((addr, stream)) = self.pending_clients.remove(&token) {
-                // assume the connection succeeded
-                try!(new_client(&mut self.clients, eloop, token, addr, stream));
+            warn!("received writable event for stale token {:?}", token);
 
-                return Ok(Action::None);
-            } else {
-                warn!("received writable event for stale token {:?}", token);
+            Ok(Action::None)
+        }
+    }
 
-                return Ok(Action::None);
+    /// handle a writable event, or a flush queued by queue_flush, for an established client found
+    /// by the key it's registered under
+    ///
+    /// At most `max` bytes are written; the flag returned says whether there's more the socket
+    /// would have taken.
+    fn client_writable (&mut self, eloop: &mut EventLoop, key: Key, max: usize) -> (Token, Result<Action, Error>, bool) {
+        let token = match self.clients.by_key(key) {
+            // a throttled connection waits for its budget instead
+            Some(&mut Connection { token, write: WriteState::Throttled, .. }) => {
+                trace!("received writable event, but writes to {:?} are throttled", token);
+                return (token, Ok(Action::None), false);
+            },
+            Some(&mut Connection { token, .. }) => token,
+            None => {
+                warn!("received writable event for stale key {:?}", key);
+                return (key.token(), Ok(Action::None), false);
+            },
+        };
+
+        let before = self.traffic(key);
+        let result = self.try_flush_up_to(eloop, token, max);
+        let waiting = match self.clients.by_key(key) {
+            Some(&mut Connection { write: WriteState::WaitingForWrite, .. }) => true,
+            _ => false,
+        };
+        let more = waiting && self.traffic(key).saturating_sub(before) >= max as u64;
+
+        (token, result, more)
+    }
+
+    /// the bytes a connection has read and written, to measure its turns by
+    fn traffic (&mut self, key: Key) -> u64 {
+        match self.clients.by_key(key) {
+            Some(&mut Connection { ref client, .. }) => client.stats.bytes_read + client.stats.bytes_written,
+            None => 0,
+        }
+    }
+
+    /// queue an established client to flush on its next turn, rather than write everything now
+    /// ahead of the connections already waiting
+    fn queue_flush (&mut self, eloop: &mut EventLoop, token: Token) {
+        let key = match self.clients.get(&token) {
+            Some(connection) => connection.key,
+            None => {
+                warn!("received flush request for stale token {:?}", token);
+                return;
+            },
+        };
+
+        self.client_ready(eloop, key, None, true);
+    }
+
+    /// note readiness for an established client, and queue it for a turn if it isn't queued
+    fn client_ready (&mut self, eloop: &mut EventLoop, key: Key, read: Option<mio::ReadHint>, write: bool) {
+        match self.clients.by_key(key) {
+            Some(connection) => {
+                connection.ready.read = match (connection.ready.read, read) {
+                    (Some(old), Some(new)) => Some(old | new),
+                    (old, new) => old.or(new),
+                };
+                connection.ready.write = connection.ready.write || write;
+
+                if connection.queued {
+                    return;
+                }
+                connection.queued = true;
+            },
+            None => {
+                warn!("received event for stale key {:?}", key);
+                return;
+            },
+        }
+
+        self.run_queue.push_back(key);
+        self.schedule_run(eloop);
+    }
+
+    /// arrange for the run queue to get a turn once the loop has handled its current events
+    fn schedule_run (&mut self, eloop: &mut EventLoop) {
+        if self.run_pending {
+            return;
+        }
+
+        match self.wake.send(InputMessage::RunQueue) {
+            Ok(_) => self.run_pending = true,
+            Err(e) => {
+                // the channel is full, so run it now rather than leave connections stuck
+                warn!("failed to schedule the run queue: {:?}", e);
+                self.run_queue(eloop);
+            },
+        }
+    }
+
+    /// give queued connections their turns, by deficit round robin
+    ///
+    /// Each turn adds QUANTUM bytes per unit of weight to the connection's deficit, and the
+    /// connection writes and then reads until the deficit is used up.  Connections that still have
+    /// work go to the back of the queue, keeping what's left of their deficit.  A pass stops after
+    /// PASS_BUDGET bytes, and schedules another.
+    fn run_queue (&mut self, eloop: &mut EventLoop) {
+        let mut moved = 0;
+
+        while moved < PASS_BUDGET {
+            let key = match self.run_queue.pop_front() {
+                Some(key) => key,
+                None => return,
+            };
+
+            let (ready, allowance) = match self.clients.by_key(key) {
+                Some(connection) => {
+                    let quantum = QUANTUM.saturating_mul(connection.client.weight as usize);
+                    connection.deficit = connection.deficit.saturating_add(quantum);
+                    (mem::replace(&mut connection.ready, Ready::default()), connection.deficit)
+                },
+
+                // closed since it was queued
+                None => continue,
+            };
+
+            let before = self.traffic(key);
+            let mut left = Ready::default();
+
+            if ready.write {
+                let (token, result, more) = self.client_writable(eloop, key, allowance);
+                left.write = more;
+                self.handle_result(eloop, token, result);
+            }
+
+            if let Some(hint) = ready.read {
+                let used = self.traffic(key).saturating_sub(before) as usize;
+
+                if used < allowance {
+                    let (token, result, more) = self.client_readable(eloop, key, hint, allowance - used);
+                    if more {
+                        left.read = Some(hint);
+                    }
+                    self.handle_result(eloop, token, result);
+                } else {
+                    left.read = Some(hint);
+                }
+            }
+
+            let used = self.traffic(key).saturating_sub(before) as usize;
+            moved += used;
+
+            let requeue = match self.clients.by_key(key) {
+                Some(connection) => {
+                    connection.ready.read = match (connection.ready.read, left.read) {
+                        (Some(old), Some(new)) => Some(old | new),
+                        (old, new) => old.or(new),
+                    };
+                    connection.ready.write = connection.ready.write || left.write;
+
+                    if connection.ready.read.is_some() || connection.ready.write {
+                        connection.deficit = allowance.saturating_sub(used);
+                        true
+                    } else {
+                        connection.deficit = 0;
+                        connection.queued = false;
+                        false
+                    }
+                },
+                None => false,
+            };
+
+            if requeue {
+                self.run_queue.push_back(key);
             }
         }
+
+        if !self.run_queue.is_empty() {
+            self.schedule_run(eloop);
+        }
     }
 
     fn handle_result (&mut self, eloop: &mut EventLoop, token: Token, res: Result<Action, Error>) {
@@ -434,42 +2317,51 @@ impl Handler {
             Err(Error::AcceptFailed) => {}, // do nothing here for now
 
             // client dirty disconnect
-            Err(Error::Io(_)) | Err(Error::ClientError) => {
-                info!("dirty disconnect client {:?}", token);
-                mio::Handler::notify(self, eloop, InputMessage::Close { token: token, dirty: true });
+            Err(Error::Failed(phase, e)) => {
+                info!("dirty disconnect client {:?}: {:?} during {:?}", token, e, phase);
+                self.lost(eloop, token, true, CloseReason::Error(phase, e));
             },
 
             // client clean disconnect
             Err(Error::ClientDisconnect) => {
                 info!("clean disconnect client {:?}", token);
-                mio::Handler::notify(self, eloop, InputMessage::Close { token: token, dirty: false });
+                self.lost(eloop, token, false, CloseReason::Hangup);
             },
 
-            // these errors cause a loop shutdown
-            Err(Error::DownstreamDisconnect) => eloop.shutdown(),
+            // there's nowhere left to send output, so shut down the way Input::Shutdown would
+            Err(Error::DownstreamDisconnect) => self.proc_shutdown(eloop),
 
             Ok(Action::None) => {}, // nothing to do here
 
-            Ok(Action::TryFlush) => {
-                let result = self.try_flush(eloop, token);  // this will never return a TryFlush
-                self.handle_result(eloop, token, result);
-            },
+            Ok(Action::TryFlush) => self.queue_flush(eloop, token),
         }
     }
 }
 
 impl mio::Handler for Handler {
-    type Timeout = ();
+    type Timeout = Timeout;
     type Message = InputMessage;
 
     fn readable (&mut self, eloop: &mut EventLoop, token: Token, hint: mio::ReadHint) {
-        let result = Handler::readable(self, eloop, token, hint);
-        self.handle_result(eloop, token, result);
+        // established clients are registered under their slab key rather than their token
+        // they wait for their turn in the run queue
+        match Key::from_token(token) {
+            Some(key) => self.client_ready(eloop, key, Some(hint), false),
+            None      => {
+                let result = Handler::readable(self, eloop, token, hint);
+                self.handle_result(eloop, token, result);
+            },
+        }
     }
 
     fn writable (&mut self, eloop: &mut EventLoop, token: Token) {
-        let result = Handler::writable(self, eloop, token);
-        self.handle_result(eloop, token, result);
+        match Key::from_token(token) {
+            Some(key) => self.client_ready(eloop, key, None, true),
+            None      => {
+                let result = Handler::writable(self, eloop, token);
+                self.handle_result(eloop, token, result);
+            },
+        }
     }
 
     fn notify (&mut self, eloop: &mut EventLoop, message: InputMessage) {
@@ -477,26 +2369,194 @@ impl mio::Handler for Handler {
             InputMessage::ListenRequest { 
                 listener: token,
                 addr,
-            } => (token, self.proc_listen_request(eloop, token, addr)),
+                options,
+                context,
+            } => (token, self.proc_listen_request(eloop, token, addr, options, context)),
 
             InputMessage::ConnectRequest {
                 token,
                 addr,
-            } => (token, self.proc_connect_request(eloop, token, addr)),
+                options,
+                context,
+            } => (token, self.proc_connect_request(eloop, token, addr, options, context)),
 
             InputMessage::Data {
                 token,
                 data,
-            } => (token, self.proc_data(token, data)),
+            } => (token, self.proc_data(token, vec![Chunk::Owned(data)])),
+
+            InputMessage::DataVectored {
+                token,
+                data,
+            } => (token, self.proc_data(token, data.into_iter().map(Chunk::Shared).collect())),
+
+            InputMessage::Broadcast {
+                tokens,
+                data,
+            } => {
+                trace!("broadcasting {:?} bytes to {:?} clients", data.len(), tokens.len());
+
+                let mut sent = HashSet::new();
+                for token in tokens {
+                    // each client gets the data once, however often it's listed
+                    if !sent.insert(token) {
+                        continue;
+                    }
+
+                    let result = self.proc_data(token, vec![Chunk::Shared(data.clone())]);
+                    self.handle_result(eloop, token, result);
+                }
+                return;
+            },
+
+            InputMessage::ConnectHostRequest {
+                token,
+                host,
+                port,
+                options,
+                context,
+            } => (token, self.proc_connect_host_request(token, host, port, options, context)),
+
+            InputMessage::Resolved {
+                token,
+                request,
+                result,
+            } => (token, self.proc_resolved(eloop, token, request, result)),
+
+            InputMessage::Accept {
+                token,
+            } => (token, self.proc_accept(eloop, token)),
+
+            InputMessage::Reject {
+                token,
+                reason,
+            } => (token, self.proc_reject(eloop, token, reason)),
+
+            InputMessage::Link {
+                a,
+                b,
+            } => (a, self.proc_link(a, b)),
 
             InputMessage::StatisticsRequest {
                 token,
             } => (token, self.proc_stats_request(token)),
 
+            InputMessage::InfoRequest {
+                token,
+            } => (token, self.proc_info_request(token)),
+
+            InputMessage::SetContext {
+                token,
+                context,
+            } => {
+                // a context set on a token that isn't in use would be handed to whatever takes the
+                // token next
+                if self.token_in_use(&token) {
+                    self.set_context(token, context);
+                } else {
+                    warn!("ignoring context for unknown token {:?}", token);
+                }
+                return;
+            },
+
+            InputMessage::SetAccessRules {
+                listener: token,
+                rules,
+            } => (token, self.proc_set_access_rules(token, rules)),
+
+            InputMessage::LoopStatisticsRequest => {
+                let result = self.proc_loop_stats_request();
+                if let Err(Error::DownstreamDisconnect) = result {
+                    self.proc_shutdown(eloop);
+                }
+                return;
+            },
+
+            InputMessage::SetRateLimits {
+                token,
+                read,
+                write,
+            } => (token, self.proc_set_rate_limits(eloop, token, read, write)),
+
+            InputMessage::SetPriority {
+                token,
+                weight,
+            } => (token, self.proc_set_priority(token, weight)),
+
+            InputMessage::RunQueue => {
+                self.run_pending = false;
+                self.run_queue(eloop);
+                return;
+            },
+
+            InputMessage::SetDownstream {
+                downstream,
+            } => {
+                self.downstream.replace(downstream);
+                return;
+            },
+
+            InputMessage::Call {
+                input,
+                reply,
+            } => {
+                let token = match input.token() {
+                    Some(token) => token,
+                    None => {
+                        warn!("received call for {:?}, which has no token to reply about", input);
+                        return;
+                    },
+                };
+
+                // connecting takes a while, but anything else is answered straight away if at all
+                let answered_later = match *input {
+                    InputMessage::ConnectRequest { .. } | InputMessage::ConnectHostRequest { .. } => true,
+                    _ => false,
+                };
+
+                let call = self.downstream.expect_reply(token, reply);
+                mio::Handler::notify(self, eloop, *input);
+
+                if !answered_later {
+                    self.downstream.cancel_reply(&token, call);
+                }
+                return;
+            },
+
+            InputMessage::SetTokenDownstream {
+                token,
+                downstream,
+            } => {
+                // a route for a token that isn't in use would take the output of whatever next
+                // takes the token, and never be forgotten until then
+                if !self.token_in_use(&token) {
+                    warn!("ignoring downstream for unknown token {:?}", token);
+                    return;
+                }
+
+                match self.downstream.reroute(token, downstream) {
+                    Err(_) => (token, Err(Error::DownstreamDisconnect)),
+                    Ok(_)  => return,
+                }
+            },
+
+            InputMessage::SetFallbackDownstream {
+                downstream,
+            } => {
+                self.downstream.set_fallback(downstream);
+                return;
+            },
+
+            #[cfg(feature = "fault-injection")]
+            InputMessage::InjectFault {
+                token,
+                fault,
+            } => (token, self.proc_inject_fault(eloop, token, fault)),
+
             InputMessage::Close {
                 token,
                 dirty,
-            } => (token, self.proc_close(eloop, token, dirty, None)),
+            } => (token, self.proc_close(eloop, token, dirty, CloseReason::Requested)),
 
             InputMessage::Shutdown => {
                 self.proc_shutdown(eloop);
@@ -506,4 +2566,57 @@ impl mio::Handler for Handler {
 
         self.handle_result(eloop, token, result);
     }
+
+    fn timeout (&mut self, eloop: &mut EventLoop, timeout: Timeout) {
+        let (token, result) = match timeout {
+            Timeout::ResumeAccept(token) => {
+                debug!("resuming accepts on {:?}", token);
+                if let Some(listener) = self.listeners.get_mut(&token) {
+                    listener.resume_timer = None;
+                }
+                (token, self.set_accepting(eloop, token, true).map(|_| Action::None))
+            },
+
+            Timeout::Accept(token) => {
+                if let Some(listener) = self.listeners.get_mut(&token) {
+                    listener.accept_timer = None;
+                }
+                (token, self.accept(eloop, token).map(|_| Action::None))
+            },
+
+            Timeout::Reconnect(token) => (token, self.reconnect(eloop, token)),
+
+            Timeout::ReadBudget(token) => (token, self.read_budget_restored(eloop, token)),
+
+            Timeout::WriteBudget(token) => (token, self.write_budget_restored(eloop, token)),
+
+            Timeout::Drain(token) => {
+                info!("timed out draining {:?}", token);
+                let reason = io::Error::new(io::ErrorKind::TimedOut, "drain timed out");
+                (token, self.proc_close(eloop, token, true, CloseReason::Error(Phase::Timeout, reason)))
+            },
+
+            #[cfg(feature = "fault-injection")]
+            Timeout::ResumeReads(token) => (token, self.resume_reads(eloop, token)),
+
+            Timeout::ConnectAttempt(token) => {
+                let reason = io::Error::new(io::ErrorKind::TimedOut, "connect attempt timed out");
+                (token, self.retry_connect(eloop, token, CloseReason::Error(Phase::Timeout, reason)))
+            },
+
+            Timeout::AcceptDecision(token) => {
+                info!("timed out waiting for an accept decision on {:?}", token);
+                let reason = io::Error::new(io::ErrorKind::TimedOut, "accept decision timed out");
+                (token, self.drop_held(eloop, token, true, CloseReason::Error(Phase::Timeout, reason)))
+            },
+
+            Timeout::ProxyHeader(token) => {
+                info!("timed out waiting for the PROXY header from {:?}", token);
+                let reason = io::Error::new(io::ErrorKind::TimedOut, "PROXY header timed out");
+                (token, self.drop_held(eloop, token, true, CloseReason::Error(Phase::Timeout, reason)))
+            },
+        };
+
+        self.handle_result(eloop, token, result);
+    }
 }
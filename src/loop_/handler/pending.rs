@@ -0,0 +1,20 @@
+use std::net;
+use mio;
+
+use ConnectOptions;
+
+use super::Stream;
+
+/// an outgoing connection that hasn't been established yet
+pub struct Pending {
+    /// the address currently being connected to
+    pub addr:       net::SocketAddr,
+    pub stream:     Stream,
+    pub options:    ConnectOptions,
+
+    /// addresses to try next if this attempt fails
+    pub candidates: Vec<net::SocketAddr>,
+
+    /// the timer that gives up on this attempt, if there is one
+    pub timeout:    Option<mio::Timeout>,
+}
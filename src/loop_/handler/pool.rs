@@ -0,0 +1,107 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use mio;
+
+use resolver::Resolver;
+use InputMessage;
+use Token;
+
+/// the most host names resolved at once; further requests wait for a thread to come free
+const THREADS: usize = 4;
+
+/// a host name to resolve for a connection
+struct Job {
+    token:   Token,
+    request: u64,
+    host:    String,
+    port:    u16,
+}
+
+/// a fixed set of threads resolving host names for the loop
+///
+/// Resolution can block for a long time, so it's kept off the loop thread.  The threads are only
+/// started by the first request, and each result is sent back to the loop as an Input::Resolved.
+/// They finish once the pool is dropped.
+pub struct ResolverPool {
+    resolver: Arc<Resolver + 'static>,
+    loop_:    mio::Sender<InputMessage>,
+    jobs:     Option<Sender<Job>>,
+}
+
+impl ResolverPool {
+    pub fn new (resolver: Arc<Resolver + 'static>, loop_: mio::Sender<InputMessage>) -> ResolverPool {
+        ResolverPool {
+            resolver: resolver,
+            loop_:    loop_,
+            jobs:     None,
+        }
+    }
+
+    /// queue a host to be resolved for the connection with `token`, tagged with `request` so that
+    /// an answer to an earlier request for the same token can be told apart
+    pub fn resolve (&mut self, token: Token, request: u64, host: String, port: u16) -> Result<(), io::Error> {
+        if self.jobs.is_none() {
+            self.jobs = Some(try!(self.start()));
+        }
+
+        let job = Job {
+            token:   token,
+            request: request,
+            host:    host,
+            port:    port,
+        };
+
+        match self.jobs.as_ref().map(|jobs| jobs.send(job)) {
+            Some(Ok(_)) => Ok(()),
+            _ => {
+                // every thread has gone, so start afresh next time
+                self.jobs = None;
+                Err(io::Error::new(io::ErrorKind::Other, "no resolver threads are running"))
+            },
+        }
+    }
+
+    fn start (&self) -> Result<Sender<Job>, io::Error> {
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+
+        for i in 0..THREADS {
+            let queue = queue.clone();
+            let resolver = self.resolver.clone();
+            let loop_ = self.loop_.clone();
+
+            try!(thread::Builder::new().name(format!("resolver-{}", i)).spawn(move || {
+                work(&*queue, &*resolver, loop_)
+            }));
+        }
+
+        Ok(jobs)
+    }
+}
+
+fn work (queue: &Mutex<Receiver<Job>>, resolver: &Resolver, loop_: mio::Sender<InputMessage>) {
+    loop {
+        // the lock is only held while waiting for a job, not while resolving it
+        let job = match queue.lock() {
+            Ok(queue) => queue.recv(),
+            Err(_) => return,
+        };
+
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let result = resolver.resolve(&job.host, job.port);
+
+        match loop_.send(InputMessage::Resolved { token: job.token, request: job.request, result: result }) {
+            Err(_) => {
+                debug!("loop went away while resolving {:?}", job.host);
+                return;
+            },
+            Ok(_) => {},
+        }
+    }
+}
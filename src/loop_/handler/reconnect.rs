@@ -0,0 +1,74 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net;
+use std::time::Instant;
+use mio;
+
+use {ConnectOptions, ReconnectPolicy};
+
+/// what an outgoing connection was asked to connect to
+#[derive(Debug, Clone)]
+pub enum Target {
+    Addr(net::SocketAddr),
+    Host(String, u16),
+}
+
+/// the reconnect state of an outgoing connection with a ReconnectPolicy
+pub struct Reconnect {
+    pub target:  Target,
+    pub options: ConnectOptions,
+    pub policy:  ReconnectPolicy,
+
+    /// the number of reconnects since the connection was last stable
+    pub attempt: u32,
+
+    /// when the current connection was established, if it is
+    pub connected_at: Option<Instant>,
+
+    /// the timer for the next reconnect, while waiting for one
+    pub timer:   Option<mio::Timeout>,
+}
+
+impl Reconnect {
+    pub fn new (target: Target, options: ConnectOptions, policy: ReconnectPolicy) -> Reconnect {
+        Reconnect {
+            target:       target,
+            options:      options,
+            policy:       policy,
+            attempt:      0,
+            connected_at: None,
+            timer:        None,
+        }
+    }
+
+    /// count another attempt, returning how long to wait before it, or None if the policy has
+    /// run out of attempts
+    pub fn next_attempt (&mut self) -> Option<u64> {
+        // a connection that stayed up long enough starts the backoff over
+        if let Some(connected_at) = self.connected_at.take() {
+            let elapsed = connected_at.elapsed();
+            let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+
+            if elapsed_ms >= self.policy.reset_after_ms {
+                self.attempt = 0;
+            }
+        }
+
+        self.attempt += 1;
+
+        if let Some(max) = self.policy.max_attempts {
+            if self.attempt > max {
+                return None;
+            }
+        }
+
+        Some(self.policy.delay_ms(self.attempt, random_unit()))
+    }
+}
+
+// a random number in [0, 1), good enough for jitter
+fn random_unit () -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,234 @@
+use std::usize;
+use mio;
+
+use Token;
+
+/// event tokens with this bit set belong to the loop rather than the downstream
+const RESERVED: usize = !(usize::MAX >> 1);
+
+/// how many bits of a key are the slot index; the rest, below RESERVED, are its generation
+///
+/// 64-bit targets get 16 million slots, and a slot can be reused half a trillion times before an
+/// old key could find a new value.  32-bit targets have far fewer bits to share: 65535 slots, and
+/// stale keys are only told apart for 32767 reuses of a slot.
+#[cfg(target_pointer_width = "64")]
+const INDEX_BITS: usize = 24;
+#[cfg(not(target_pointer_width = "64"))]
+const INDEX_BITS: usize = 16;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: usize = (usize::MAX >> 1) >> INDEX_BITS;
+
+/// the position of a value in a Slab, along with the generation of the slot when it was inserted
+///
+/// A key whose slot has since been emptied, and maybe reused, no longer finds anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    index:      usize,
+    generation: usize,
+}
+
+impl Key {
+    /// the token to register with the event loop, so events lead straight back to the slot
+    pub fn token (&self) -> Token {
+        mio::Token(RESERVED | self.generation << INDEX_BITS | self.index)
+    }
+
+    /// the key an event token was made from, if it was made from one
+    pub fn from_token (token: Token) -> Option<Key> {
+        if is_reserved(token) {
+            Some(Key {
+                index:      token.0 & INDEX_MASK,
+                generation: (token.0 & !RESERVED) >> INDEX_BITS,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// the generation a slot moves on to once it's emptied
+///
+/// mio keeps Token(usize::MAX) for itself, so the generation with every bit set is skipped, and
+/// no key can make that token whatever its index.
+fn next_generation (generation: usize) -> usize {
+    match (generation + 1) & GENERATION_MASK {
+        GENERATION_MASK => 0,
+        next => next,
+    }
+}
+
+/// whether a token is in the range the loop keeps for its own event tokens
+pub fn is_reserved (token: Token) -> bool {
+    token.0 & RESERVED != 0
+}
+
+struct Slot<T> {
+    generation: usize,
+    value:      Option<T>,
+}
+
+/// values stored in a vector, with emptied slots reused
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    free:  Vec<usize>,
+    len:   usize,
+
+    /// the most slots there may be; the last index is left unused, along with the top generation
+    limit: usize,
+}
+
+impl<T> Slab<T> {
+    pub fn new () -> Slab<T> {
+        Slab::with_limit(INDEX_MASK)
+    }
+
+    fn with_limit (limit: usize) -> Slab<T> {
+        Slab {
+            slots: Vec::new(),
+            free:  Vec::new(),
+            len:   0,
+            limit: limit,
+        }
+    }
+
+    pub fn len (&self) -> usize {
+        self.len
+    }
+
+    /// store the value made by `f`, which is given the key the value will be found under
+    ///
+    /// Returns None, without calling `f`, if every slot is taken.
+    pub fn insert_with<F: FnOnce(Key) -> T> (&mut self, f: F) -> Option<Key> {
+        let index = match self.free.pop() {
+            Some(index) => index,
+
+            None if self.slots.len() >= self.limit => return None,
+
+            None => {
+                self.slots.push(Slot { generation: 0, value: None });
+                self.slots.len() - 1
+            },
+        };
+
+        let key = Key {
+            index:      index,
+            generation: self.slots[index].generation,
+        };
+
+        self.slots[index].value = Some(f(key));
+        self.len += 1;
+
+        Some(key)
+    }
+
+    pub fn get (&self, key: Key) -> Option<&T> {
+        match self.slots.get(key.index) {
+            Some(slot) if slot.generation == key.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut (&mut self, key: Key) -> Option<&mut T> {
+        match self.slots.get_mut(key.index) {
+            Some(slot) if slot.generation == key.generation => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn remove (&mut self, key: Key) -> Option<T> {
+        let value = match self.slots.get_mut(key.index) {
+            Some(slot) if slot.generation == key.generation => match slot.value.take() {
+                Some(value) => {
+                    // anything still holding the old key now misses
+                    slot.generation = next_generation(slot.generation);
+                    value
+                },
+                None => return None,
+            },
+            _ => return None,
+        };
+
+        self.free.push(key.index);
+        self.len -= 1;
+
+        Some(value)
+    }
+
+    pub fn iter_mut<'a> (&'a mut self) -> Box<Iterator<Item = &'a mut T> + 'a> {
+        Box::new(self.slots.iter_mut().filter_map(|slot| slot.value.as_mut()))
+    }
+
+    /// remove everything, leaving every outstanding key stale
+    pub fn clear (&mut self) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.value.take().is_some() {
+                slot.generation = next_generation(slot.generation);
+                self.free.push(index);
+            }
+        }
+
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::usize;
+
+    use super::{next_generation, Key, Slab, GENERATION_MASK, INDEX_BITS, INDEX_MASK};
+
+    #[test]
+    fn tokens_lead_back_to_keys () {
+        let mut slab = Slab::new();
+        let key = slab.insert_with(|_| "a").unwrap();
+
+        assert_eq!(Key::from_token(key.token()), Some(key));
+        assert_eq!(Key::from_token(::mio::Token(1)), None);
+    }
+
+    #[test]
+    fn stale_keys_miss () {
+        let mut slab = Slab::new();
+        let old = slab.insert_with(|_| "a").unwrap();
+        assert_eq!(slab.remove(old), Some("a"));
+
+        let new = slab.insert_with(|_| "b").unwrap();
+        assert!(new != old);
+        assert_eq!(slab.get(old), None);
+        assert_eq!(slab.remove(old), None);
+        assert_eq!(slab.get(new), Some(&"b"));
+    }
+
+    #[test]
+    fn never_makes_the_reserved_token () {
+        assert_eq!(next_generation(GENERATION_MASK - 1), 0);
+        assert_eq!(next_generation(0), 1);
+
+        let key = Key { index: INDEX_MASK - 1, generation: GENERATION_MASK - 1 };
+        assert!(key.token().0 != usize::MAX);
+    }
+
+    #[test]
+    fn generations_last () {
+        // at least 32767 reuses of a slot before a stale key could match, whatever the target
+        assert!(GENERATION_MASK >= (1 << 15) - 1);
+        assert_eq!(GENERATION_MASK << INDEX_BITS | INDEX_MASK, usize::MAX >> 1);
+    }
+
+    #[test]
+    fn full_slabs_refuse () {
+        let mut slab = Slab::with_limit(8);
+        for _ in 0..8 {
+            slab.insert_with(|_| ()).unwrap();
+        }
+
+        let mut called = false;
+        assert_eq!(slab.insert_with(|_| called = true), None);
+        assert!(!called);
+
+        // room again once a slot is freed
+        let key = Key { index: 7, generation: 0 };
+        assert_eq!(slab.remove(key), Some(()));
+        assert_eq!(slab.insert_with(|_| ()), Some(Key { index: 7, generation: 1 }));
+    }
+}
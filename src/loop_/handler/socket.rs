@@ -0,0 +1,309 @@
+use std::io;
+use std::net;
+use mio;
+
+use ConnectOptions;
+use info::{SocketOptions, TcpInfo};
+
+use super::Stream;
+
+#[cfg(target_os = "linux")]
+mod sockopt {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn set_flag<S: AsRawFd> (socket: &S, level: libc::c_int, name: libc::c_int) -> Result<(), io::Error> {
+        let value: libc::c_int = 1;
+
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// whether the options ask for anything that has to be set on the socket before it connects
+#[cfg(target_os = "linux")]
+fn needs_socket_flags (options: &ConnectOptions) -> bool {
+    options.freebind || options.transparent
+}
+
+#[cfg(not(target_os = "linux"))]
+fn needs_socket_flags (_: &ConnectOptions) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn set_socket_flags (socket: &mio::tcp::TcpSocket, v6: bool, options: &ConnectOptions) -> Result<(), io::Error> {
+    use libc;
+    use self::sockopt::set_flag;
+
+    let (level, freebind, transparent) = if v6 {
+        (libc::SOL_IPV6, libc::IPV6_FREEBIND, libc::IPV6_TRANSPARENT)
+    } else {
+        (libc::SOL_IP, libc::IP_FREEBIND, libc::IP_TRANSPARENT)
+    };
+
+    if options.freebind {
+        try!(set_flag(socket, level, freebind));
+    }
+
+    if options.transparent {
+        try!(set_flag(socket, level, transparent));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_socket_flags (_: &mio::tcp::TcpSocket, _: bool, _: &ConnectOptions) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// the most chunks gathered into a single write
+pub const MAX_IOVECS: usize = 64;
+
+/// write several slices to a socket with a single writev, returning how much was written, or
+/// None if the socket would block
+#[cfg(unix)]
+pub fn write_vectored (stream: &mut Stream, slices: &[&[u8]]) -> Result<Option<usize>, io::Error> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    let iovecs: Vec<libc::iovec> = slices.iter().map(|slice| libc::iovec {
+        iov_base: slice.as_ptr() as *mut libc::c_void,
+        iov_len:  slice.len(),
+    }).collect();
+
+    let stream: &mio::tcp::TcpStream = stream;
+    let ret = unsafe {
+        libc::writev(stream.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int)
+    };
+
+    if ret >= 0 {
+        return Ok(Some(ret as usize));
+    }
+
+    let e = io::Error::last_os_error();
+    match e.kind() {
+        io::ErrorKind::WouldBlock  => Ok(None),
+        io::ErrorKind::Interrupted => Ok(Some(0)),
+        _ => Err(e),
+    }
+}
+
+/// without writev, only the first slice is written
+#[cfg(not(unix))]
+pub fn write_vectored (stream: &mut Stream, slices: &[&[u8]]) -> Result<Option<usize>, io::Error> {
+    use mio::TryWrite;
+
+    match slices.first() {
+        Some(slice) => stream.write_slice(slice),
+        None => Ok(Some(0)),
+    }
+}
+
+/// whether an error from accept means the process or system is out of descriptors or memory, so
+/// that trying again straight away would only fail the same way
+#[cfg(unix)]
+pub fn out_of_descriptors (e: &io::Error) -> bool {
+    use libc;
+
+    match e.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => true,
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn out_of_descriptors (_: &io::Error) -> bool {
+    false
+}
+
+/// start connecting to an address, with the local end set up as the options ask
+pub fn connect (addr: &net::SocketAddr, options: &ConnectOptions) -> Result<(Stream, bool), io::Error> {
+    if options.bind.is_none() && !needs_socket_flags(options) {
+        return mio::tcp::connect(addr);
+    }
+
+    let v6 = match *addr {
+        net::SocketAddr::V4(_) => false,
+        net::SocketAddr::V6(_) => true,
+    };
+
+    let socket = try!(if v6 { mio::tcp::v6() } else { mio::tcp::v4() });
+
+    try!(set_socket_flags(&socket, v6, options));
+
+    if let Some(ref local) = options.bind {
+        try!(socket.bind(local));
+    }
+
+    socket.connect(addr)
+}
+
+#[cfg(unix)]
+mod getsockopt {
+    use libc;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn get<S: AsRawFd, T: Copy> (socket: &S, level: libc::c_int, name: libc::c_int, mut value: T) -> Result<T, io::Error> {
+        let mut len = mem::size_of::<T>() as libc::socklen_t;
+
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut T as *mut libc::c_void,
+                &mut len)
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub fn get_int<S: AsRawFd> (socket: &S, level: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+        get(socket, level, name, 0 as libc::c_int).ok()
+    }
+}
+
+/// read the options of a connected socket
+#[cfg(unix)]
+pub fn socket_options (stream: &Stream) -> SocketOptions {
+    use libc;
+    use self::getsockopt::get_int;
+
+    let stream: &mio::tcp::TcpStream = stream;
+
+    SocketOptions {
+        nodelay:     get_int(stream, libc::IPPROTO_TCP, libc::TCP_NODELAY).map(|x| x != 0),
+        keepalive:   get_int(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE).map(|x| x != 0),
+        send_buffer: get_int(stream, libc::SOL_SOCKET, libc::SO_SNDBUF).map(|x| x as usize),
+        recv_buffer: get_int(stream, libc::SOL_SOCKET, libc::SO_RCVBUF).map(|x| x as usize),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn socket_options (_: &Stream) -> SocketOptions {
+    Default::default()
+}
+
+/// take the pending error of a socket that the event loop flagged as failed
+#[cfg(unix)]
+pub fn take_error (stream: &Stream) -> io::Error {
+    use libc;
+    use self::getsockopt::get_int;
+
+    let stream: &mio::tcp::TcpStream = stream;
+
+    match get_int(stream, libc::SOL_SOCKET, libc::SO_ERROR) {
+        Some(errno) if errno != 0 => io::Error::from_raw_os_error(errno),
+        _ => io::Error::new(io::ErrorKind::Other, "socket error"),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn take_error (_: &Stream) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "socket error")
+}
+
+// the leading, long-stable part of linux's struct tcp_info; the kernel fills in as much as fits
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct RawTcpInfo {
+    state:          u8,
+    ca_state:       u8,
+    retransmits:    u8,
+    probes:         u8,
+    backoff:        u8,
+    options:        u8,
+    wscale:         u8,
+    flags:          u8,
+
+    rto:            u32,
+    ato:            u32,
+    snd_mss:        u32,
+    rcv_mss:        u32,
+
+    unacked:        u32,
+    sacked:         u32,
+    lost:           u32,
+    retrans:        u32,
+    fackets:        u32,
+
+    last_data_sent: u32,
+    last_ack_sent:  u32,
+    last_data_recv: u32,
+    last_ack_recv:  u32,
+
+    pmtu:           u32,
+    rcv_ssthresh:   u32,
+    rtt:            u32,
+    rttvar:         u32,
+    snd_ssthresh:   u32,
+    snd_cwnd:       u32,
+    advmss:         u32,
+    reordering:     u32,
+
+    rcv_rtt:        u32,
+    rcv_space:      u32,
+
+    total_retrans:  u32,
+}
+
+/// read the kernel's TCP_INFO for a connected socket
+#[cfg(target_os = "linux")]
+pub fn tcp_info (stream: &Stream) -> Option<TcpInfo> {
+    use libc;
+    use std::mem;
+
+    let stream: &mio::tcp::TcpStream = stream;
+    let raw: RawTcpInfo = match getsockopt::get(stream, libc::IPPROTO_TCP, libc::TCP_INFO, unsafe { mem::zeroed() }) {
+        Ok(raw) => raw,
+        Err(e) => {
+            debug!("failed to read TCP_INFO: {:?}", e);
+            return None;
+        },
+    };
+
+    Some(TcpInfo {
+        state:         raw.state,
+        retransmits:   raw.retransmits,
+        rto_us:        raw.rto,
+        snd_mss:       raw.snd_mss,
+        rcv_mss:       raw.rcv_mss,
+        unacked:       raw.unacked,
+        lost:          raw.lost,
+        retrans:       raw.retrans,
+        pmtu:          raw.pmtu,
+        rtt_us:        raw.rtt,
+        rttvar_us:     raw.rttvar,
+        snd_cwnd:      raw.snd_cwnd,
+        total_retrans: raw.total_retrans,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_info (_: &Stream) -> Option<TcpInfo> {
+    None
+}
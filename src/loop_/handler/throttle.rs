@@ -0,0 +1,158 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use RateLimit;
+
+use super::bucket::TokenBucket;
+
+/// a throttle isn't lifted until this much budget is back, so a slow rate isn't served a byte at a
+/// time
+const RESUME_BYTES: u64 = 1024;
+
+/// how far apart the event loop's timer ticks are, in milliseconds (mio's default); a throttled
+/// client gets its budget back no more often than this
+const TIMER_TICK_MS: u64 = 100;
+
+/// limits one direction of a client's traffic to a RateLimit
+pub struct Throttle {
+    bucket: TokenBucket,
+    resume: u64,
+
+    /// when the client was last stopped for want of budget, if it's stopped now
+    since:  Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new (limit: RateLimit) -> Throttle {
+        // with no rate at all the budget would never come back, and the timer waiting for it
+        // would fire straight away, over and over
+        let rate = cmp::max(limit.bytes_per_second, 1);
+
+        // the bucket has to hold a tick's worth, or whatever a tick refills past a small burst is
+        // lost and the rate is never reached
+        let capacity = cmp::max(limit.burst, rate.saturating_mul(TIMER_TICK_MS) / 1000);
+
+        Throttle {
+            bucket: TokenBucket::new(rate, capacity),
+            resume: cmp::max(cmp::min(capacity, RESUME_BYTES), 1),
+            since:  None,
+        }
+    }
+
+    /// how many bytes may go through now
+    pub fn budget (&mut self) -> usize {
+        cmp::min(self.bucket.available(), usize::max_value() as u64) as usize
+    }
+
+    pub fn spend (&mut self, bytes: usize) {
+        self.bucket.take(bytes as u64);
+    }
+
+    pub fn paused (&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// stop until there's some budget again, returning how long that will be
+    pub fn pause (&mut self) -> Duration {
+        if self.since.is_none() {
+            self.since = Some(Instant::now());
+        }
+
+        self.bucket.time_until(self.resume)
+    }
+
+    /// lift the throttle, returning how long it was in place
+    pub fn resume (&mut self) -> Duration {
+        match self.since.take() {
+            Some(since) => since.elapsed(),
+            None => Duration::from_millis(0),
+        }
+    }
+
+    /// how long the throttle has been in place so far
+    pub fn paused_for (&self) -> Duration {
+        match self.since {
+            Some(since) => since.elapsed(),
+            None => Duration::from_millis(0),
+        }
+    }
+}
+
+/// measures a rate in bytes per second over windows of about a second
+pub struct Meter {
+    start: Instant,
+    bytes: u64,
+    rate:  u64,
+}
+
+impl Meter {
+    pub fn new () -> Meter {
+        Meter {
+            start: Instant::now(),
+            bytes: 0,
+            rate:  0,
+        }
+    }
+
+    pub fn record (&mut self, bytes: usize) {
+        let elapsed = self.start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.rate = per_second(self.bytes, elapsed);
+            self.start = Instant::now();
+            self.bytes = 0;
+        }
+
+        self.bytes += bytes as u64;
+    }
+
+    /// the rate over the last full window; traffic that has stopped reads as 0
+    pub fn rate (&self) -> u64 {
+        let elapsed = self.start.elapsed();
+
+        if elapsed >= Duration::from_secs(2) {
+            0
+        } else if elapsed >= Duration::from_secs(1) {
+            per_second(self.bytes, elapsed)
+        } else {
+            self.rate
+        }
+    }
+}
+
+fn per_second (bytes: u64, elapsed: Duration) -> u64 {
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    (bytes as f64 / secs) as u64
+}
+
+pub fn millis (duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use RateLimit;
+
+    use super::{Throttle, TIMER_TICK_MS};
+
+    #[test]
+    fn zero_rate_waits () {
+        let mut throttle = Throttle::new(RateLimit { bytes_per_second: 0, burst: 0 });
+
+        let budget = throttle.budget();
+        throttle.spend(budget);
+
+        assert_eq!(throttle.budget(), 0);
+        assert!(throttle.pause() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn small_bursts_hold_a_tick () {
+        let mut throttle = Throttle::new(RateLimit { bytes_per_second: 1_000_000, burst: 0 });
+        assert_eq!(throttle.budget() as u64, 1_000_000 * TIMER_TICK_MS / 1000);
+
+        let mut throttle = Throttle::new(RateLimit { bytes_per_second: 1_000, burst: 5_000 });
+        assert_eq!(throttle.budget(), 5_000);
+    }
+}
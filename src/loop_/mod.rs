@@ -2,12 +2,15 @@ use mio;
 use std::io;
 use std::sync::mpsc::Sender;
 
+mod handle;
 mod handler;
 
 use self::handler::Handler;
-use {TokenFactory, InputMessage, OutputMessage};
+use {DownstreamPolicy, TokenFactory, InputMessage, OutputMessage};
+use resolver::{Resolver, SystemResolver};
 
-pub use self::handler::ClientStatistics;
+pub use self::handle::LoopHandle;
+pub use self::handler::{ClientStatistics, LoopStatistics};
 pub type EventLoop = mio::EventLoop<Handler>;
 
 pub struct Loop {
@@ -17,8 +20,13 @@ pub struct Loop {
 
 impl Loop {
     pub fn new<F: TokenFactory + 'static> (factory: F, downstream: Sender<OutputMessage>) -> Result<Loop, io::Error> {
+        Loop::with_resolver(factory, downstream, SystemResolver)
+    }
+
+    /// create a loop that resolves the hosts in Input::ConnectHostRequest with `resolver`
+    pub fn with_resolver<F: TokenFactory + 'static, R: Resolver + 'static> (factory: F, downstream: Sender<OutputMessage>, resolver: R) -> Result<Loop, io::Error> {
         let eloop = try!(EventLoop::new());
-        let handler = Handler::new(factory, downstream);
+        let handler = Handler::new(factory, downstream, resolver, eloop.channel());
 
         Ok(Loop {
             eloop: eloop,
@@ -32,6 +40,18 @@ impl Loop {
         self.eloop.channel()
     }
 
+    /// create a blocking interface to the loop, producing tokens from `factory`
+    ///
+    /// `factory` should share its tokens with the one the loop was created with; see LoopHandle.
+    pub fn handle<F: TokenFactory + 'static> (&self, factory: F) -> LoopHandle {
+        LoopHandle::new(self.channel(), factory)
+    }
+
+    /// choose what happens once the downstream's receiver has gone away
+    pub fn set_downstream_policy (&mut self, policy: DownstreamPolicy) {
+        self.handler.set_downstream_policy(policy);
+    }
+
     pub fn run (&mut self) -> Result<(), io::Error> {
         Ok(try!(self.eloop.run(&mut self.handler)))
     }
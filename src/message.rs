@@ -1,32 +1,107 @@
-use ClientStatistics;
-use Token;
+use {AccessRules, ClientStatistics, ConnectOptions, ConnectionInfo, ConnectionState, ListenOptions, LoopStatistics, ProxyHeader};
+use {OutputMessage, RateLimit, Token, UserContext};
 
 use std::{io, net};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+
+#[cfg(feature = "fault-injection")]
+use fault::Fault;
 
 #[derive(Debug)]
 pub enum Input {
     /// request that the loop listen on an address
     ///
     /// If the listen succeeds, an Output::ListenResponse will be sent to
-    /// the downstream.  If it fails, an Output::Close will be sent instead.
+    /// the downstream.  If it fails, an Output::DirtyClose will be sent instead, with the error
+    /// in Phase::Register.
     ListenRequest {
         /// the token to associate with this listener
         listener: Token,
 
         /// the address to listen on
         addr:     net::SocketAddr,
+
+        /// limits and other settings for the listener
+        options:  ListenOptions,
+
+        /// a value to attach to the listener's token, returned with every Output message for it
+        ///
+        /// Connections accepted by the listener start out with the same value.
+        context:  Option<UserContext>,
     },
 
     /// request that the loop establish a connection to an address
     ///
     /// If the connection succeeds, an Output::ConnectResponse will be sent to
-    /// the downstream.  If it fails, an Output::Disconnect will be sent instead.
+    /// the downstream.  If it fails, an Output::DirtyClose will be sent instead.
     ConnectRequest {
         /// the token to associate with this connection
-        token: Token,
+        token:   Token,
 
         /// the address to connect to
-        addr:  net::SocketAddr,
+        addr:    net::SocketAddr,
+
+        /// settings for the connection
+        options: ConnectOptions,
+
+        /// a value to attach to the token, returned with every Output message for it
+        context: Option<UserContext>,
+    },
+
+    /// request that the loop establish a connection to a host by name
+    ///
+    /// The host is resolved off the loop thread, and each address it resolves to is tried in
+    /// turn, alternating between the two address families (starting with the family of the first
+    /// address the resolver returned), until one connects.  The Output::ConnectResponse
+    /// says which address succeeded.  If resolution fails or no address connects, an
+    /// Output::DirtyClose is sent with the last error.
+    ConnectHostRequest {
+        /// the token to associate with this connection
+        token:   Token,
+
+        /// the name of the host to connect to
+        host:    String,
+
+        /// the port to connect to
+        port:    u16,
+
+        /// settings for the connection
+        options: ConnectOptions,
+
+        /// a value to attach to the token, returned with every Output message for it
+        context: Option<UserContext>,
+    },
+
+    /// the result of resolving the host of an Input::ConnectHostRequest
+    ///
+    /// This is sent to the loop by its own resolver threads, and isn't meant for downstreams.
+    #[doc(hidden)]
+    Resolved {
+        token:   Token,
+        request: u64,
+        result:  Result<Vec<net::SocketAddr>, io::Error>,
+    },
+
+    /// let a held connection through
+    ///
+    /// Only applies to connections accepted by a listener in manual accept mode.  The loop
+    /// starts reading from the connection once this is received.
+    Accept {
+        /// the token associated with the held connection
+        token: Token,
+    },
+
+    /// turn away a held connection
+    ///
+    /// Only applies to connections accepted by a listener in manual accept mode.  The connection
+    /// is closed without anything being read from it, and an Output::DirtyClose is sent.
+    Reject {
+        /// the token associated with the held connection
+        token:  Token,
+
+        /// why the connection was turned away
+        reason: String,
     },
 
     /// send some data to a client
@@ -40,19 +115,208 @@ pub enum Input {
         data:  Vec<u8>,
     },
 
+    /// send several pieces of data to a client, one after the other
+    ///
+    /// The pieces are queued as they are rather than copied into one buffer, so the same piece
+    /// can go out in messages for many clients, for example a shared body behind a header of
+    /// each client's own.  Queued pieces are written together, up to 64 at a time, with a single
+    /// writev where the platform has one.
+    DataVectored {
+        /// the token associated with the connection to send data over
+        token: Token,
+
+        /// the pieces to send, in order
+        data:  Vec<Arc<[u8]>>,
+    },
+
+    /// send the same data to several clients
+    ///
+    /// Each client's queue holds a reference to `data` rather than a copy of it.  Tokens that
+    /// aren't established connections are skipped, and a token listed more than once is only
+    /// sent the data once.
+    Broadcast {
+        /// the tokens associated with the connections to send data over
+        tokens: Vec<Token>,
+
+        /// the data to send
+        data:   Arc<[u8]>,
+    },
+
+    /// join two connections so that data read from one is written to the other
+    ///
+    /// Once linked, data read from either connection is queued directly to the other inside the
+    /// loop, and no Output::Data is produced for either token.  If one side's write buffer backs
+    /// up, reading from the other side is paused until it drains.  When one side hangs up, the
+    /// write half of the other side is shut down once its buffer has been flushed.  Once both
+    /// sides have hung up, or either side is closed or fails, both connections are closed and an
+    /// Output::Close (or Output::DirtyClose) is sent for each token; the side left behind first
+    /// writes whatever it still has queued from the other, within its drain timeout.
+    Link {
+        /// the token associated with one connection
+        a: Token,
+
+        /// the token associated with the other connection
+        b: Token,
+    },
+
     /// request statistics for a connection
     ///
     /// If the token is associated with a present and valid connection, an
-    /// Output::StatisticsResponse will be sent to the downstream.
+    /// Output::StatisticsResponse will be sent to the downstream.  Connections that haven't
+    /// connected yet report empty statistics along with their state.
     StatisticsRequest {
         /// the token associated with the connection
         token: Token,
     },
 
+    /// request a description of a connection
+    ///
+    /// If the token is associated with a present and valid connection, an Output::InfoResponse
+    /// will be sent to the downstream.
+    InfoRequest {
+        /// the token associated with the connection
+        token: Token,
+    },
+
+    /// replace the value attached to a token
+    ///
+    /// Output messages for the token carry the new value from then on.  Setting it to None
+    /// detaches the old value.  Changing a listener's value doesn't affect clients it has already
+    /// accepted.  Ignored for a token that no listener or connection is using.
+    SetContext {
+        /// the token associated with the connection or listener
+        token:   Token,
+
+        /// the new value
+        context: Option<UserContext>,
+    },
+
+    /// replace the access rules of a listener
+    ///
+    /// The new rules apply to connections accepted from then on; clients that are already
+    /// connected are left alone.
+    SetAccessRules {
+        /// the token associated with the listener
+        listener: Token,
+
+        /// the new rules
+        rules:    AccessRules,
+    },
+
+    /// request statistics for the loop as a whole
+    ///
+    /// An Output::LoopStatisticsResponse will be sent to the downstream.
+    LoopStatisticsRequest,
+
+    /// change the rate limits of a connection, or of the clients a listener accepts from now on
+    ///
+    /// None removes a limit.  See ListenOptions::read_limit and ListenOptions::write_limit.
+    SetRateLimits {
+        /// the token associated with the connection or listener
+        token: Token,
+
+        /// the new limit on reading
+        read:  Option<RateLimit>,
+
+        /// the new limit on writing
+        write: Option<RateLimit>,
+    },
+
+    /// change the weight of a connection, or of the clients a listener accepts from now on
+    ///
+    /// See ListenOptions::weight.
+    SetPriority {
+        /// the token associated with the connection or listener
+        token:  Token,
+
+        /// the new weight; 0 counts as 1
+        weight: u32,
+    },
+
+    /// give the connections with readiness waiting their turn
+    ///
+    /// This is sent to the loop by itself, and isn't meant for downstreams.
+    #[doc(hidden)]
+    RunQueue,
+
+    /// send output to a new downstream from now on
+    ///
+    /// Everything the loop produced before this message was processed goes to the old
+    /// downstream, followed by an Output::Handoff with no token; everything after goes to the new
+    /// one.  Anything the loop held on to while it had no downstream (see
+    /// DownstreamPolicy::Buffer) is sent to the new one first.
+    ///
+    /// Tokens with a downstream of their own keep it.
+    SetDownstream {
+        downstream: Sender<OutputMessage>,
+    },
+
+    /// send the output for one listener or connection to a different downstream
+    ///
+    /// The ordering is the same as for Input::SetDownstream: the token's old downstream gets
+    /// everything produced for it up to this point, then an Output::Handoff naming the token.
+    /// A listener's downstream also gets the Output::ConnectRequest and
+    /// Output::ConnectionRejected messages for its incoming connections, but not the output of
+    /// the connections themselves.
+    ///
+    /// With `downstream` set to None, the token's output goes back to the main downstream.  The
+    /// token's downstream is forgotten once its Output::Close or Output::DirtyClose has been
+    /// sent, and its output falls back to the main downstream if it goes away.
+    ///
+    /// Ignored for a token that no listener or connection is using; the downstream given is
+    /// dropped without being sent anything.
+    SetTokenDownstream {
+        /// the token associated with the listener or connection
+        token:      Token,
+
+        /// where its output goes from now on
+        downstream: Option<Sender<OutputMessage>>,
+    },
+
+    /// register the downstream to switch to under DownstreamPolicy::Fallback
+    SetFallbackDownstream {
+        downstream: Sender<OutputMessage>,
+    },
+
+    /// process a request, sending the reply to it down a channel of its own
+    ///
+    /// The reply is whichever of Output::ListenResponse, Output::ConnectResponse,
+    /// Output::StatisticsResponse, Output::InfoResponse, Output::TokenCollision, Output::Close
+    /// or Output::DirtyClose comes first for the request's token; it goes to `reply` instead of
+    /// the downstream.  Requests that are answered straight away drop `reply` if there's no
+    /// answer, such as an Input::StatisticsRequest for an unknown token.  Everything else the
+    /// request produces goes to the downstream as usual.
+    ///
+    /// This is what LoopHandle is built on.
+    Call {
+        /// the request
+        input: Box<Input>,
+
+        /// where the reply goes
+        reply: Sender<OutputMessage>,
+    },
+
+    /// inject a fault into an established connection
+    ///
+    /// Only available with the `fault-injection` feature.
+    #[cfg(feature = "fault-injection")]
+    InjectFault {
+        /// the token associated with the connection
+        token: Token,
+
+        /// the fault
+        fault: Fault,
+    },
+
     /// request that a connection should be closed
     ///
     /// Can apply to either a listener or client.  The loop will send an Output::Close
     /// once the connection has been closed.
+    ///
+    /// A clean close of a client that still has data queued drains the queue first: the client
+    /// enters ConnectionState::Draining, and is closed once everything has been written.  If
+    /// that takes longer than the connection's drain_timeout_ms, it's closed anyway with an
+    /// Output::DirtyClose.
     Close {
         /// the token associated with the connection or listener to close
         token: Token,
@@ -68,6 +332,127 @@ pub enum Input {
     Shutdown,
 }
 
+impl Input {
+    /// the token the request is about, if it's about one
+    pub fn token (&self) -> Option<Token> {
+        match *self {
+            Input::ListenRequest { listener, .. } |
+            Input::SetAccessRules { listener, .. } => Some(listener),
+
+            Input::ConnectRequest { token, .. } |
+            Input::ConnectHostRequest { token, .. } |
+            Input::Resolved { token, .. } |
+            Input::Accept { token, .. } |
+            Input::Reject { token, .. } |
+            Input::Data { token, .. } |
+            Input::DataVectored { token, .. } |
+            Input::StatisticsRequest { token, .. } |
+            Input::InfoRequest { token, .. } |
+            Input::SetContext { token, .. } |
+            Input::SetTokenDownstream { token, .. } |
+            Input::SetRateLimits { token, .. } |
+            Input::SetPriority { token, .. } |
+            Input::Close { token, .. } => Some(token),
+
+            #[cfg(feature = "fault-injection")]
+            Input::InjectFault { token, .. } => Some(token),
+
+            Input::Link { a, .. } => Some(a),
+
+            Input::Call { ref input, .. } => input.token(),
+
+            Input::Broadcast { .. } |
+            Input::LoopStatisticsRequest |
+            Input::RunQueue |
+            Input::SetDownstream { .. } |
+            Input::SetFallbackDownstream { .. } |
+            Input::Shutdown => None,
+        }
+    }
+}
+
+/// why a listener turned away an incoming connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    /// the listener already has its maximum number of clients
+    MaxClients,
+
+    /// the listener has accepted too many connections recently
+    AcceptRate,
+
+    /// the peer's address already has its maximum number of clients
+    MaxClientsPerIp,
+
+    /// the peer's address is not permitted by the listener's access rules
+    AccessDenied,
+
+    /// the token factory produced a token that's already in use
+    TokenInUse,
+}
+
+/// the part of a connection's life an error happened in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// reading from the connection
+    Read,
+
+    /// writing to the connection, or shutting down its write half
+    Write,
+
+    /// setting up a listener, or registering a connection with the event loop
+    Register,
+
+    /// resolving a host name or establishing an outgoing connection
+    Connect,
+
+    /// waiting for something that didn't happen in time
+    Timeout,
+
+    /// parsing what the peer sent, such as a PROXY protocol header
+    Codec,
+
+    /// a decision to turn the connection away, such as an Input::Reject
+    Policy,
+}
+
+/// why a connection or listener was closed, carried in every Output::Close and
+/// Output::DirtyClose
+#[derive(Debug)]
+pub enum CloseReason {
+    /// the downstream asked for it with an Input::Close
+    Requested,
+
+    /// the peer hung up, or both sides of a link did
+    Hangup,
+
+    /// the other side of a link was closed
+    LinkClosed,
+
+    /// the loop is shutting down
+    Shutdown,
+
+    /// something went wrong; the error is the one that was originally reported
+    Error(Phase, io::Error),
+}
+
+impl CloseReason {
+    /// the phase an error happened in, if the close was caused by one
+    pub fn phase (&self) -> Option<Phase> {
+        match *self {
+            CloseReason::Error(phase, _) => Some(phase),
+            _ => None,
+        }
+    }
+
+    /// the error that caused the close, if any
+    pub fn error (&self) -> Option<&io::Error> {
+        match *self {
+            CloseReason::Error(_, ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Output {
     /// indicate that a listener has been established
@@ -77,6 +462,9 @@ pub enum Output {
         /// the token associated with the listener, specified by the Input::ListenRequest that
         /// created this listener
         listener: Token,
+
+        /// the value attached to the listener
+        context:  Option<UserContext>,
     },
 
     /// notify the downstream that a client connection has been accepted
@@ -85,6 +473,10 @@ pub enum Output {
     /// successfully being accepted by a listener.  By the time this message is produced, this
     /// connection has already been recorded in the loop's internal record, and has been registered
     /// in the event loop.
+    ///
+    /// If the listener is in manual accept mode, the connection is held without being read from
+    /// until the downstream replies with an Input::Accept or Input::Reject.  If no reply arrives in
+    /// time, the connection is closed and an Output::DirtyClose is sent.
     ConnectRequest {
         /// the token associated with the listener that accepted the connection
         listener: Token,
@@ -94,6 +486,37 @@ pub enum Output {
 
         /// the address of the peer
         addr:     net::SocketAddr,
+
+        /// the local address the connection was accepted on, if it could be determined
+        local:    Option<net::SocketAddr>,
+
+        /// the PROXY protocol header the connection started with, if the listener expects one
+        ///
+        /// When present, the header's source is the original client's address, while `addr` is
+        /// the address of the proxy.
+        proxy:    Option<ProxyHeader>,
+
+        /// the value attached to the connection, inherited from the listener
+        context:  Option<UserContext>,
+    },
+
+    /// notify the downstream that a listener turned away an incoming connection
+    ///
+    /// This message is generated when a connection is accepted while its listener is over one
+    /// of its limits, or from an address its access rules don't permit, and is closed
+    /// immediately, without a token being assigned to it.
+    ConnectionRejected {
+        /// the token associated with the listener that turned the connection away
+        listener: Token,
+
+        /// the address of the peer
+        addr:     net::SocketAddr,
+
+        /// the limit that was exceeded
+        reason:   RejectReason,
+
+        /// the value attached to the listener
+        context:  Option<UserContext>,
     },
 
     /// indicate that an outgoing connection has succeeded
@@ -104,6 +527,34 @@ pub enum Output {
     ConnectResponse {
         /// the token associated with the connection, as specified in the Input::ConnectRequest
         token: Token,
+
+        /// the address that was connected to
+        addr:  net::SocketAddr,
+
+        /// the local address the connection was made from, if it could be determined
+        local:   Option<net::SocketAddr>,
+
+        /// the value attached to the connection
+        context: Option<UserContext>,
+    },
+
+    /// notify the downstream that an outgoing connection is about to be re-established
+    ///
+    /// This message is only generated for connections with a ReconnectPolicy, when the
+    /// connection fails or is lost.  Data sent to the token is discarded until the next
+    /// Output::ConnectResponse.
+    Reconnecting {
+        /// the token associated with the connection
+        token:    Token,
+
+        /// the number of attempts since the connection was last stable, starting at 1
+        attempt:  u32,
+
+        /// how long until the attempt is made, in milliseconds
+        delay_ms: u64,
+
+        /// the value attached to the connection
+        context:  Option<UserContext>,
     },
 
     /// notify the downstream that data has been read from a connection
@@ -115,7 +566,23 @@ pub enum Output {
         token: Token,
 
         /// the data read from the connection
-        data:  Vec<u8>,
+        data:    Vec<u8>,
+
+        /// the value attached to the connection
+        context: Option<UserContext>,
+    },
+
+    /// notify the downstream that a request named a token that's already in use
+    ///
+    /// This message is sent instead of acting on an Input::ListenRequest, Input::ConnectRequest
+    /// or Input::ConnectHostRequest whose token already belongs to a listener or connection.  The
+    /// existing listener or connection is left alone.
+    TokenCollision {
+        /// the token that's already in use
+        token:   Token,
+
+        /// the value the request would have attached to the token
+        context: Option<UserContext>,
     },
 
     /// send statistics for a client to the downstream
@@ -124,7 +591,56 @@ pub enum Output {
         token: Token,
 
         /// the statistics
-        stats: ClientStatistics,
+        stats:   ClientStatistics,
+
+        /// the state the connection is in
+        state:   ConnectionState,
+
+        /// the value attached to the connection
+        context: Option<UserContext>,
+    },
+
+    /// notify the downstream that a connection has moved to a new state
+    ///
+    /// Only sent for connections that asked for it in their ConnectOptions or ListenOptions.  The
+    /// state a connection is announced in isn't reported: outgoing connections and held clients
+    /// start out Connecting, and other accepted clients start out Established.  The change to
+    /// ConnectionState::Closed is sent just before the Output::Close or Output::DirtyClose.
+    StateChanged {
+        /// the token associated with the connection
+        token:   Token,
+
+        /// the state the connection is now in
+        state:   ConnectionState,
+
+        /// the value attached to the connection
+        context: Option<UserContext>,
+    },
+
+    /// send a description of a connection to the downstream
+    InfoResponse {
+        /// the token associated with the connection
+        token: Token,
+
+        /// the description
+        info:    ConnectionInfo,
+
+        /// the value attached to the connection
+        context: Option<UserContext>,
+    },
+
+    /// send statistics for the loop as a whole to the downstream
+    LoopStatisticsResponse {
+        /// the statistics
+        stats: LoopStatistics,
+    },
+
+    /// the last message sent to this downstream before output moved elsewhere
+    ///
+    /// Sent in response to an Input::SetDownstream, or an Input::SetTokenDownstream.
+    Handoff {
+        /// the token whose output moved, or None if it was the main downstream that was replaced
+        token: Option<Token>,
     },
 
     /// notify the downstream that a connection has ended cleanly or a listener has stopped
@@ -138,18 +654,68 @@ pub enum Output {
     /// requested by the downstream.
     Close {
         /// the token associated with the connection or listener that has closed
-        token:  Token,
+        token:   Token,
+
+        /// why the connection was closed
+        reason:  CloseReason,
+
+        /// the value that was attached to the token; it's detached once this is sent
+        context: Option<UserContext>,
     },
 
     /// notify the downstream that a connection ended uncleanly
     ///
-    /// This message is generated when a connection ends as a result of some sort of error.  In
-    /// most cases the error is included in the message.
+    /// This message is generated when a connection ends as a result of some sort of error, or
+    /// when the downstream asks for a dirty close.
     DirtyClose {
         /// the token associated with the connection that ended
-        token:  Token,
+        token:   Token,
+
+        /// why the connection ended, including the error and the phase it happened in
+        reason:  CloseReason,
+
+        /// the value that was attached to the token; it's detached once this is sent
+        context: Option<UserContext>,
+    }
+}
+
+impl Output {
+    /// the token whose downstream the message goes to
+    ///
+    /// That's the listener for messages about its incoming connections.
+    pub fn token (&self) -> Option<Token> {
+        match *self {
+            Output::ListenResponse { listener, .. } |
+            Output::ConnectRequest { listener, .. } |
+            Output::ConnectionRejected { listener, .. } => Some(listener),
+
+            Output::ConnectResponse { token, .. } |
+            Output::Reconnecting { token, .. } |
+            Output::Data { token, .. } |
+            Output::TokenCollision { token, .. } |
+            Output::StatisticsResponse { token, .. } |
+            Output::StateChanged { token, .. } |
+            Output::InfoResponse { token, .. } |
+            Output::Close { token, .. } |
+            Output::DirtyClose { token, .. } => Some(token),
+
+            Output::Handoff { token } => token,
+
+            Output::LoopStatisticsResponse { .. } => None,
+        }
+    }
 
-        /// if present, the error associated with the end
-        reason: Option<io::Error>,
+    /// whether the message can answer an Input::Call
+    pub fn is_reply (&self) -> bool {
+        match *self {
+            Output::ListenResponse { .. } |
+            Output::ConnectResponse { .. } |
+            Output::StatisticsResponse { .. } |
+            Output::InfoResponse { .. } |
+            Output::TokenCollision { .. } |
+            Output::Close { .. } |
+            Output::DirtyClose { .. } => true,
+            _ => false,
+        }
     }
 }
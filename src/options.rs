@@ -0,0 +1,303 @@
+use std::cmp;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use {Cidr, ProxyHeader};
+
+/// what the loop does when the downstream's receiver has gone away
+///
+/// Set with Loop::set_downstream_policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DownstreamPolicy {
+    /// close every connection and stop the loop
+    Shutdown,
+
+    /// keep running, holding up to this many messages until an Input::SetDownstream arrives
+    ///
+    /// The loop shuts down if the limit is reached first.
+    Buffer(usize),
+
+    /// switch to the downstream registered with Input::SetFallbackDownstream
+    ///
+    /// The fallback is only used once; the loop shuts down if there's none, or it goes away too.
+    Fallback,
+}
+
+impl Default for DownstreamPolicy {
+    fn default () -> DownstreamPolicy {
+        DownstreamPolicy::Shutdown
+    }
+}
+
+/// a limit on the rate of one direction of a connection's traffic
+///
+/// Enforced with a token bucket: the connection may go `burst` bytes above the rate before it's
+/// held back.  A rate of 0 is taken as 1 byte per second.  The loop's timers tick every 100ms, so
+/// a burst smaller than a tenth of a second at the rate is raised to that; a smaller one would cap
+/// the rate at about ten bursts a second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    pub burst:            u64,
+}
+
+/// what a listener does with incoming connections once it is over one of its limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverLimit {
+    /// stop accepting until the listener is back under its limits
+    ///
+    /// Connections are left in the kernel's backlog in the meantime.  The per-address limit
+    /// can't be known before accepting, so connections over it are always rejected.
+    Pause,
+
+    /// accept the connection and close it immediately
+    Reject,
+}
+
+/// address-based access control for a listener
+///
+/// A peer matching any deny rule is turned away.  Otherwise, if there are any allow rules, the
+/// peer must match one of them to be let in.
+#[derive(Debug, Clone, Default)]
+pub struct AccessRules {
+    /// ranges that may connect; if empty, every address not denied may connect
+    pub allow: Vec<Cidr>,
+
+    /// ranges that may not connect, even if they are also allowed
+    pub deny:  Vec<Cidr>,
+}
+
+impl AccessRules {
+    /// whether a peer at this address may connect
+    pub fn permits (&self, addr: &IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+/// per-listener settings, given with an Input::ListenRequest
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    /// the maximum number of clients accepted by this listener that may be connected at once
+    pub max_clients: Option<usize>,
+
+    /// the maximum number of clients from a single address that may be connected at once
+    pub max_clients_per_ip: Option<usize>,
+
+    /// the maximum sustained number of connections accepted per second
+    pub accept_rate: Option<u32>,
+
+    /// the number of connections that may be accepted in a burst above accept_rate
+    pub accept_burst: u32,
+
+    /// what to do with connections that arrive while over a limit
+    pub over_limit: OverLimit,
+
+    /// which peer addresses may connect
+    pub access: AccessRules,
+
+    /// whether the downstream decides if each accepted connection is kept
+    ///
+    /// In this mode, accepted connections are held without being read from until the
+    /// downstream replies to the Output::ConnectRequest with an Input::Accept or Input::Reject.
+    pub manual_accept: bool,
+
+    /// how long a held connection waits for a decision before it is rejected, in milliseconds
+    pub accept_timeout_ms: u64,
+
+    /// the most connections accepted for a single readiness event
+    pub accept_batch: usize,
+
+    /// whether the listener and the clients it accepts are registered edge-triggered
+    pub edge_triggered: bool,
+
+    /// whether accepted connections start with a PROXY protocol (v1 or v2) header
+    ///
+    /// The header is read and parsed before the connection is announced with an
+    /// Output::ConnectRequest.  Connections with a malformed header are closed with an
+    /// Output::DirtyClose.
+    pub proxy_protocol: bool,
+
+    /// how long a client has to send its whole PROXY header before it is closed, in milliseconds
+    ///
+    /// Only used with proxy_protocol.  The client is closed with an Output::DirtyClose giving a
+    /// Phase::Timeout error.
+    pub header_timeout_ms: u64,
+
+    /// whether the clients accepted by the listener send an Output::StateChanged each time their
+    /// ConnectionState changes
+    pub report_state: bool,
+
+    /// a directory to record the traffic of each accepted client in; see the capture module
+    pub capture: Option<PathBuf>,
+
+    /// how fast each accepted client may be read from
+    ///
+    /// Reading from a client stops once it's over the limit, and starts again when it's back
+    /// under.
+    pub read_limit: Option<RateLimit>,
+
+    /// how fast each accepted client may be written to
+    ///
+    /// Data over the limit stays queued until there's budget for it.
+    pub write_limit: Option<RateLimit>,
+
+    /// each accepted client's share of the loop when several have data waiting
+    ///
+    /// Clients take turns reading and writing; each turn, a client may move 16 KiB for every unit
+    /// of weight.  0 counts as 1.
+    pub weight: u32,
+
+    /// how long a clean close may wait for a client's queue to be written, in milliseconds
+    ///
+    /// A client still Draining after this long is closed anyway, with an Output::DirtyClose
+    /// giving a Phase::Timeout error.
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for ListenOptions {
+    fn default () -> ListenOptions {
+        ListenOptions {
+            max_clients:        None,
+            max_clients_per_ip: None,
+            accept_rate:        None,
+            accept_burst:       1,
+            over_limit:         OverLimit::Pause,
+            access:             Default::default(),
+            manual_accept:      false,
+            accept_timeout_ms:  10_000,
+            accept_batch:       32,
+            edge_triggered:     false,
+            proxy_protocol:     false,
+            header_timeout_ms:  5_000,
+            report_state:       false,
+            capture:            None,
+            read_limit:         None,
+            write_limit:        None,
+            weight:             1,
+            drain_timeout_ms:   30_000,
+        }
+    }
+}
+
+/// how an outgoing connection is re-established after it fails or is lost
+///
+/// Delays grow exponentially from `initial_delay_ms`, by `multiplier` each attempt, up to
+/// `max_delay_ms`.  Each delay is then reduced by a random fraction of up to `jitter`, so that
+/// many connections lost at once don't all come back at once.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// the delay before the first reconnect, in milliseconds
+    pub initial_delay_ms: u64,
+
+    /// the longest delay between reconnects, in milliseconds
+    pub max_delay_ms: u64,
+
+    /// how much the delay grows each attempt
+    pub multiplier: f64,
+
+    /// the largest fraction of each delay that may be randomly taken off, between 0 and 1
+    pub jitter: f64,
+
+    /// how many attempts to make before giving up; if not set, attempts never stop
+    pub max_attempts: Option<u32>,
+
+    /// how long a connection has to stay up, in milliseconds, for the attempts to start over
+    pub reset_after_ms: u64,
+}
+
+impl ReconnectPolicy {
+    /// the delay before attempt number `attempt` (starting at 1), given a random number in [0, 1)
+    pub fn delay_ms (&self, attempt: u32, random: f64) -> u64 {
+        let exponent = cmp::min(attempt.saturating_sub(1), i32::max_value() as u32) as i32;
+        let delay = (self.initial_delay_ms as f64 * self.multiplier.powi(exponent)).min(self.max_delay_ms as f64);
+        let jitter = self.jitter.max(0.0).min(1.0);
+
+        (delay * (1.0 - jitter * random)) as u64
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default () -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay_ms: 100,
+            max_delay_ms:     30_000,
+            multiplier:       2.0,
+            jitter:           0.5,
+            max_attempts:     None,
+            reset_after_ms:   10_000,
+        }
+    }
+}
+
+/// per-connection settings, given with an Input::ConnectRequest
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// whether the connection is registered edge-triggered
+    pub edge_triggered: bool,
+
+    /// the local address and port to connect from
+    ///
+    /// A port of 0 lets the operating system pick one.  If not set, both are picked by the
+    /// operating system.
+    pub bind: Option<SocketAddr>,
+
+    /// allow binding to a local address that isn't (yet) configured on the host
+    ///
+    /// Sets IP_FREEBIND (or IPV6_FREEBIND).  Only available on linux.
+    #[cfg(target_os = "linux")]
+    pub freebind: bool,
+
+    /// allow binding to a foreign address, for transparent proxying
+    ///
+    /// Sets IP_TRANSPARENT (or IPV6_TRANSPARENT), which needs CAP_NET_ADMIN.  Only available on
+    /// linux.
+    #[cfg(target_os = "linux")]
+    pub transparent: bool,
+
+    /// how long to wait on each address before moving on to the next, in milliseconds
+    ///
+    /// If not set, each attempt lasts until the operating system gives up on it.
+    pub attempt_timeout_ms: Option<u64>,
+
+    /// a PROXY protocol header to send as soon as the connection is established
+    ///
+    /// This passes the identity of an original client on to upstreams that understand the
+    /// protocol.  If the header has no destination, the address being connected to is used.  A
+    /// header too long to encode fails the connection attempt.
+    pub proxy_header: Option<ProxyHeader>,
+
+    /// if set, the connection is re-established whenever it fails or is lost
+    ///
+    /// The token stays the same throughout.  An Output::Reconnecting is sent before each
+    /// attempt, and an Output::ConnectResponse each time the connection comes back.  The
+    /// connection is only closed for good once the policy runs out of attempts, or the
+    /// downstream closes it.
+    pub reconnect: Option<ReconnectPolicy>,
+
+    /// whether an Output::StateChanged is sent each time the connection's ConnectionState changes
+    pub report_state: bool,
+
+    /// a directory to record the connection's traffic in; see the capture module
+    ///
+    /// A connection that's re-established gets a new file each time.
+    pub capture: Option<PathBuf>,
+
+    /// how fast the connection may be read from; see ListenOptions::read_limit
+    pub read_limit: Option<RateLimit>,
+
+    /// how fast the connection may be written to; see ListenOptions::write_limit
+    pub write_limit: Option<RateLimit>,
+
+    /// the connection's share of the loop; see ListenOptions::weight
+    pub weight: u32,
+
+    /// how long a clean close may wait for the queue to be written; see
+    /// ListenOptions::drain_timeout_ms
+    ///
+    /// If not set, 30 seconds.
+    pub drain_timeout_ms: Option<u64>,
+}
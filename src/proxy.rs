@@ -0,0 +1,507 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::u16;
+
+/// the signature every PROXY protocol v2 header starts with
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// the prefix of every PROXY protocol v1 header
+const V1_PREFIX: &'static [u8] = b"PROXY ";
+
+/// the longest a v1 header may be, including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// which version of the PROXY protocol a header uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    /// the human-readable text format
+    V1,
+
+    /// the binary format
+    V2,
+}
+
+/// a PROXY protocol header, as sent by a load balancer ahead of the proxied connection's data
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    /// the version the header was (or will be) encoded with
+    pub version:     Version,
+
+    /// the address of the original client, if the proxy reported one
+    pub source:      Option<SocketAddr>,
+
+    /// the address the original client connected to, if the proxy reported one
+    pub destination: Option<SocketAddr>,
+
+    /// any type-length-value extensions; only present in v2 headers
+    pub tlvs:        Vec<(u8, Vec<u8>)>,
+}
+
+impl Header {
+    /// create a header describing a connection from `source` to `destination`
+    pub fn new (version: Version, source: SocketAddr, destination: SocketAddr) -> Header {
+        Header {
+            version:     version,
+            source:      Some(source),
+            destination: Some(destination),
+            tlvs:        Vec::new(),
+        }
+    }
+
+    /// encode the header to be sent ahead of a connection's data
+    ///
+    /// If either address is missing, the header says the addresses are unknown.  TLVs are only
+    /// included in v2 headers, where a TLV, or the addresses and TLVs together, can't be longer
+    /// than 65535 bytes; a longer one fails with InvalidInput.
+    pub fn encode (&self) -> Result<Vec<u8>, io::Error> {
+        match self.version {
+            Version::V1 => Ok(self.encode_v1()),
+            Version::V2 => self.encode_v2(),
+        }
+    }
+
+    // both addresses, in the same family
+    fn addresses (&self) -> Option<(SocketAddr, SocketAddr)> {
+        match (self.source, self.destination) {
+            (Some(source), Some(destination)) => match (source.ip(), destination.ip()) {
+                (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => Some((source, destination)),
+                _ => Some((to_ipv6(source), to_ipv6(destination))),
+            },
+            _ => None,
+        }
+    }
+
+    fn encode_v1 (&self) -> Vec<u8> {
+        let line = match self.addresses() {
+            Some((source, destination)) => format!("PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(), destination.ip(), source.port(), destination.port()),
+            None => "PROXY UNKNOWN\r\n".to_string(),
+        };
+
+        line.into_bytes()
+    }
+
+    fn encode_v2 (&self) -> Result<Vec<u8>, io::Error> {
+        let mut body = Vec::new();
+
+        let family = match self.addresses() {
+            Some((source, destination)) => {
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        body.extend_from_slice(&s.octets());
+                        body.extend_from_slice(&d.octets());
+                    },
+                    (IpAddr::V6(s), IpAddr::V6(d)) => {
+                        body.extend_from_slice(&s.octets());
+                        body.extend_from_slice(&d.octets());
+                    },
+                    _ => unreachable!(),
+                }
+                write_u16(&mut body, source.port());
+                write_u16(&mut body, destination.port());
+
+                if source.is_ipv4() { 0x11 } else { 0x21 }
+            },
+
+            // AF_UNSPEC
+            None => 0x00,
+        };
+
+        for &(kind, ref value) in self.tlvs.iter() {
+            if value.len() > u16::MAX as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("TLV {:#x} is {} bytes long", kind, value.len())));
+            }
+
+            body.push(kind);
+            write_u16(&mut body, value.len() as u16);
+            body.extend_from_slice(value);
+        }
+
+        if body.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("PROXY header is {} bytes long", body.len())));
+        }
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x21);  // version 2, PROXY command
+        header.push(family);
+        write_u16(&mut header, body.len() as u16);
+        header.extend(body);
+
+        Ok(header)
+    }
+}
+
+/// the error produced when a connection doesn't start with a valid PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseError;
+
+/// try to parse a PROXY protocol header from the start of a buffer
+///
+/// Returns the header and the number of bytes it took up, or None if the buffer doesn't hold a
+/// whole header yet.
+pub fn parse (buf: &[u8]) -> Result<Option<(Header, usize)>, ParseError> {
+    if starts_with(buf, V2_SIGNATURE) {
+        if buf.len() < V2_SIGNATURE.len() {
+            return Ok(None);
+        }
+        parse_v2(buf)
+    } else if starts_with(buf, V1_PREFIX) {
+        if buf.len() < V1_PREFIX.len() {
+            return Ok(None);
+        }
+        parse_v1(buf)
+    } else {
+        Err(ParseError)
+    }
+}
+
+// whether buf and prefix agree for as far as both go
+fn starts_with (buf: &[u8], prefix: &[u8]) -> bool {
+    buf.iter().zip(prefix.iter()).all(|(a, b)| a == b)
+}
+
+fn parse_v1 (buf: &[u8]) -> Result<Option<(Header, usize)>, ParseError> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LENGTH => return Err(ParseError),
+        None => return Ok(None),
+    };
+
+    if end + 2 > V1_MAX_LENGTH {
+        return Err(ParseError);
+    }
+
+    let line = match str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => return Err(ParseError),
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let (source, destination) = match (parts.get(1).map(|x| *x), parts.len()) {
+        (Some("UNKNOWN"), _) => (None, None),
+        (Some("TCP4"), 6) | (Some("TCP6"), 6) => {
+            let v6 = parts[1] == "TCP6";
+            let source = try!(parse_v1_addr(parts[2], parts[4], v6));
+            let destination = try!(parse_v1_addr(parts[3], parts[5], v6));
+            (Some(source), Some(destination))
+        },
+        _ => return Err(ParseError),
+    };
+
+    Ok(Some((Header {
+        version:     Version::V1,
+        source:      source,
+        destination: destination,
+        tlvs:        Vec::new(),
+    }, end + 2)))
+}
+
+fn parse_v1_addr (ip: &str, port: &str, v6: bool) -> Result<SocketAddr, ParseError> {
+    let ip: IpAddr = match ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => return Err(ParseError),
+    };
+
+    let port: u16 = match port.parse() {
+        Ok(port) => port,
+        Err(_) => return Err(ParseError),
+    };
+
+    match (ip, v6) {
+        (IpAddr::V4(_), false) | (IpAddr::V6(_), true) => Ok(SocketAddr::new(ip, port)),
+        _ => Err(ParseError),
+    }
+}
+
+fn parse_v2 (buf: &[u8]) -> Result<Option<(Header, usize)>, ParseError> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xf;
+    let family  = buf[13] >> 4;
+    let length  = (buf[14] as usize) << 8 | buf[15] as usize;
+
+    if version != 2 || command > 1 {
+        return Err(ParseError);
+    }
+
+    if buf.len() < 16 + length {
+        return Ok(None);
+    }
+
+    let body = &buf[16..16 + length];
+
+    // a LOCAL command is sent by the proxy itself, e.g. for health checks, and carries no addresses
+    if command == 0 {
+        return Ok(Some((Header {
+            version:     Version::V2,
+            source:      None,
+            destination: None,
+            tlvs:        Vec::new(),
+        }, 16 + length)));
+    }
+
+    let (source, destination, rest) = match family {
+        // AF_INET
+        1 if body.len() >= 12 => {
+            let source = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let destination = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            (
+                Some(SocketAddr::new(IpAddr::V4(source), read_u16(&body[8..]))),
+                Some(SocketAddr::new(IpAddr::V4(destination), read_u16(&body[10..]))),
+                &body[12..],
+            )
+        },
+
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let source = read_ipv6(&body[0..16]);
+            let destination = read_ipv6(&body[16..32]);
+            (
+                Some(SocketAddr::new(IpAddr::V6(source), read_u16(&body[32..]))),
+                Some(SocketAddr::new(IpAddr::V6(destination), read_u16(&body[34..]))),
+                &body[36..],
+            )
+        },
+
+        // AF_UNIX addresses don't fit in a SocketAddr, so they're skipped
+        3 if body.len() >= 216 => (None, None, &body[216..]),
+
+        // AF_UNSPEC
+        0 => (None, None, &body[..0]),
+
+        _ => return Err(ParseError),
+    };
+
+    Ok(Some((Header {
+        version:     Version::V2,
+        source:      source,
+        destination: destination,
+        tlvs:        try!(parse_tlvs(rest)),
+    }, 16 + length)))
+}
+
+fn parse_tlvs (mut buf: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, ParseError> {
+    let mut tlvs = Vec::new();
+
+    while buf.len() > 0 {
+        if buf.len() < 3 {
+            return Err(ParseError);
+        }
+
+        let kind = buf[0];
+        let length = read_u16(&buf[1..]) as usize;
+
+        if buf.len() < 3 + length {
+            return Err(ParseError);
+        }
+
+        tlvs.push((kind, buf[3..3 + length].to_vec()));
+        buf = &buf[3 + length..];
+    }
+
+    Ok(tlvs)
+}
+
+fn read_u16 (buf: &[u8]) -> u16 {
+    (buf[0] as u16) << 8 | buf[1] as u16
+}
+
+fn write_u16 (buf: &mut Vec<u8>, x: u16) {
+    buf.push((x >> 8) as u8);
+    buf.push(x as u8);
+}
+
+fn to_ipv6 (addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_)  => addr,
+    }
+}
+
+fn read_ipv6 (buf: &[u8]) -> Ipv6Addr {
+    let mut segments = [0u16; 8];
+    for (i, segment) in segments.iter_mut().enumerate() {
+        *segment = read_u16(&buf[i * 2..]);
+    }
+
+    Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                  segments[4], segments[5], segments[6], segments[7])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use super::{parse, parse_tlvs, read_u16, Header, ParseError, Version, V1_MAX_LENGTH, V1_PREFIX, V2_SIGNATURE};
+
+    fn addr (s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn v2_header () -> Header {
+        let mut header = Header::new(Version::V2, addr("192.0.2.1:5000"), addr("198.51.100.2:443"));
+        header.tlvs.push((0x01, b"h2".to_vec()));
+        header.tlvs.push((0x04, Vec::new()));
+        header
+    }
+
+    #[test]
+    fn parses_v1 () {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 443\r\nGET /";
+        let (header, length) = parse(buf).unwrap().unwrap();
+
+        assert_eq!(header, Header::new(Version::V1, addr("192.0.2.1:5000"), addr("198.51.100.2:443")));
+        assert_eq!(&buf[length..], b"GET /");
+
+        let (header, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 443\r\n").unwrap().unwrap();
+        assert_eq!(header.source, Some(addr("[2001:db8::1]:5000")));
+
+        let (header, length) = parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!((header.source, header.destination, length), (None, None, 15));
+    }
+
+    #[test]
+    fn v1_round_trip () {
+        let header = Header::new(Version::V1, addr("[2001:db8::1]:5000"), addr("[2001:db8::2]:443"));
+        let encoded = header.encode().unwrap();
+
+        assert_eq!(parse(&encoded).unwrap(), Some((header, encoded.len())));
+    }
+
+    #[test]
+    fn waits_for_truncated_v1 () {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 443\r\n";
+
+        for end in 0..buf.len() {
+            assert_eq!(parse(&buf[..end]), Ok(None), "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_v1 () {
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Err(ParseError));
+        assert_eq!(parse(b"PROXY TCP5 192.0.2.1 198.51.100.2 5000 443\r\n"), Err(ParseError));
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 5000\r\n"), Err(ParseError));
+        assert_eq!(parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 5000 443\r\n"), Err(ParseError));
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 5000 65536\r\n"), Err(ParseError));
+
+        // no CRLF within the longest a header can be
+        let long = vec![b' '; V1_MAX_LENGTH];
+        assert_eq!(parse(&[V1_PREFIX, &long[..]].concat()), Err(ParseError));
+    }
+
+    #[test]
+    fn v2_round_trip () {
+        let header = v2_header();
+        let mut buf = header.encode().unwrap();
+        let length = buf.len();
+        buf.extend_from_slice(b"GET /");
+
+        assert_eq!(parse(&buf).unwrap(), Some((header, length)));
+
+        let header = Header::new(Version::V2, addr("[2001:db8::1]:5000"), addr("[2001:db8::2]:443"));
+        let encoded = header.encode().unwrap();
+        assert_eq!(parse(&encoded).unwrap(), Some((header, encoded.len())));
+    }
+
+    #[test]
+    fn v2_rejects_oversized_tlvs () {
+        let mut header = v2_header();
+        header.tlvs = vec![(0x01, vec![0; 65536])];
+        assert_eq!(header.encode().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // each fits, but not all together
+        header.tlvs = vec![(0x01, vec![0; 40000]), (0x02, vec![0; 40000])];
+        assert_eq!(header.encode().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // the longest that fits still parses
+        header.tlvs = vec![(0x01, vec![0; 65535 - 12 - 3])];
+        let encoded = header.encode().unwrap();
+        assert_eq!(parse(&encoded).unwrap(), Some((header, encoded.len())));
+    }
+
+    #[test]
+    fn v2_mixed_families_are_mapped () {
+        let header = Header::new(Version::V2, addr("192.0.2.1:5000"), addr("[2001:db8::2]:443"));
+        let (parsed, _) = parse(&header.encode().unwrap()).unwrap().unwrap();
+
+        assert_eq!(parsed.source, Some(addr("[::ffff:192.0.2.1]:5000")));
+        assert_eq!(parsed.destination, header.destination);
+    }
+
+    #[test]
+    fn v2_local_command () {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend_from_slice(&[0x20, 0x11, 0x00, 0x0c]);
+        buf.extend_from_slice(&[0; 12]);
+
+        let (header, length) = parse(&buf).unwrap().unwrap();
+        assert_eq!((header.source, header.destination, length), (None, None, 28));
+    }
+
+    #[test]
+    fn waits_for_truncated_v2 () {
+        let buf = v2_header().encode().unwrap();
+
+        for end in 0..buf.len() {
+            assert_eq!(parse(&buf[..end]), Ok(None), "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_v2 () {
+        let good = v2_header().encode().unwrap();
+
+        // a signature that goes wrong partway through
+        let mut buf = good.clone();
+        buf[6] = b'X';
+        assert_eq!(parse(&buf), Err(ParseError));
+        assert_eq!(parse(&buf[..8]), Err(ParseError));
+
+        // version 1 in the binary format
+        let mut buf = good.clone();
+        buf[12] = 0x11;
+        assert_eq!(parse(&buf), Err(ParseError));
+
+        // an unknown command
+        let mut buf = good.clone();
+        buf[12] = 0x22;
+        assert_eq!(parse(&buf), Err(ParseError));
+
+        // an unknown family
+        let mut buf = good.clone();
+        buf[13] = 0x41;
+        assert_eq!(parse(&buf), Err(ParseError));
+
+        // too short for the addresses of its family
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0x00, 0x08]);
+        buf.extend_from_slice(&[0; 8]);
+        assert_eq!(parse(&buf), Err(ParseError));
+    }
+
+    #[test]
+    fn rejects_truncated_tlvs () {
+        assert_eq!(parse_tlvs(&[0x01, 0x00, 0x02, b'h', b'2']), Ok(vec![(0x01, b"h2".to_vec())]));
+
+        // a TLV that claims more than the header holds
+        assert_eq!(parse_tlvs(&[0x01, 0x00, 0x03, b'h', b'2']), Err(ParseError));
+
+        // not even room for a TLV's type and length
+        assert_eq!(parse_tlvs(&[0x01, 0x00]), Err(ParseError));
+
+        // the same, inside a whole header whose length covers only part of the last TLV
+        let mut buf = v2_header().encode().unwrap();
+        let length = read_u16(&buf[14..]) - 1;
+        buf[14] = (length >> 8) as u8;
+        buf[15] = length as u8;
+        buf.pop();
+        assert_eq!(parse(&buf), Err(ParseError));
+    }
+}
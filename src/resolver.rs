@@ -0,0 +1,44 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// turns host names into addresses for Input::ConnectHostRequest
+///
+/// Resolution runs on a small pool of threads off the loop thread, so implementations are free
+/// to block, though a slow lookup holds up the others waiting for a thread.
+pub trait Resolver: Sync + Send {
+    fn resolve (&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, io::Error>;
+}
+
+/// a resolver that uses the system's name resolution
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve (&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, io::Error> {
+        Ok(try!((host, port).to_socket_addrs()).collect())
+    }
+}
+
+/// order addresses so that the two families alternate, starting with the first one given
+///
+/// If one family is unreachable, the next attempt is then always in the other family.
+pub fn interleave (addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(|addr| addr.is_ipv6()).unwrap_or(false);
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+
+    first.reverse();
+    second.reverse();
+
+    let mut ret = Vec::new();
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => break,
+            (a, b) => {
+                ret.extend(a);
+                ret.extend(b);
+            },
+        }
+    }
+
+    ret
+}
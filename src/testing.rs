@@ -0,0 +1,361 @@
+//! support for testing code built on the loop
+//!
+//! Only built with the `test-support` feature.  A TestLoop runs a Loop on a thread of its own
+//! and collects its output, with helpers that wait a bounded time for the next message; a Peer
+//! is the other end of a connection, driven step by step or from a script.
+//!
+//! Everything here panics instead of returning errors, since it's meant to be used from tests.
+
+use mio;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {DownstreamPolicy, InputMessage, Loop, LoopHandle, OutputMessage, RecyclingTokenFactory};
+use {Token, TokenFactory};
+
+/// how long to wait for something that should happen before giving up, in milliseconds
+pub const TIMEOUT_MS: u64 = 5_000;
+
+/// a Loop running on its own thread
+pub struct TestLoop {
+    pub input:   mio::Sender<InputMessage>,
+    pub output:  Receiver<OutputMessage>,
+    pub handle:  LoopHandle,
+    factory:     RecyclingTokenFactory,
+    thread:      Option<thread::JoinHandle<()>>,
+}
+
+impl TestLoop {
+    pub fn start () -> TestLoop {
+        TestLoop::with_policy(DownstreamPolicy::default())
+    }
+
+    /// start a loop that treats losing its downstream according to `policy`
+    pub fn with_policy (policy: DownstreamPolicy) -> TestLoop {
+        let (downstream, output) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        let factory = RecyclingTokenFactory::new();
+        let loop_factory = factory.clone();
+
+        let thread = thread::spawn(move || {
+            let mut eloop = Loop::new(loop_factory.clone(), downstream).unwrap();
+            eloop.set_downstream_policy(policy);
+            tx.send((eloop.channel(), eloop.handle(loop_factory))).unwrap();
+            eloop.run().unwrap();
+        });
+
+        let (input, handle) = rx.recv().unwrap();
+
+        TestLoop {
+            input:   input,
+            output:  output,
+            handle:  handle,
+            factory: factory,
+            thread:  Some(thread),
+        }
+    }
+
+    /// a token no listener or connection is using
+    pub fn token (&mut self) -> Token {
+        self.factory.produce()
+    }
+
+    pub fn send (&self, message: InputMessage) {
+        if self.input.send(message).is_err() {
+            panic!("the loop isn't running");
+        }
+    }
+
+    /// the next output message, or None if there's none in time
+    pub fn next (&self) -> Option<OutputMessage> {
+        self.next_within(TIMEOUT_MS)
+    }
+
+    pub fn next_within (&self, ms: u64) -> Option<OutputMessage> {
+        match self.output.recv_timeout(Duration::from_millis(ms)) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("the loop has stopped"),
+        }
+    }
+
+    /// skip output until a message matching `wanted` arrives, and return it
+    pub fn wait_for<F: Fn(&OutputMessage) -> bool> (&self, what: &str, wanted: F) -> OutputMessage {
+        let deadline = Instant::now() + Duration::from_millis(TIMEOUT_MS);
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                panic!("timed out waiting for {}", what);
+            }
+
+            let left = deadline - now;
+            let ms = left.as_secs() * 1000 + left.subsec_nanos() as u64 / 1_000_000;
+            match self.next_within(ms) {
+                Some(message) => if wanted(&message) {
+                    return message;
+                } else {
+                    debug!("skipping {:?} while waiting for {}", message, what);
+                },
+                None => panic!("timed out waiting for {}", what),
+            }
+        }
+    }
+
+    /// check that nothing is output for a while
+    pub fn expect_quiet (&self, ms: u64) {
+        if let Some(message) = self.next_within(ms) {
+            panic!("expected no output, got {:?}", message);
+        }
+    }
+
+    /// wait until the loop has handled everything sent to it so far
+    ///
+    /// Input messages are handled in order, so once the answer to a request sent now arrives,
+    /// the ones before it have taken effect.  Panics if other output arrives first.
+    pub fn sync (&self) {
+        self.send(InputMessage::LoopStatisticsRequest);
+
+        match self.next() {
+            Some(OutputMessage::LoopStatisticsResponse { .. }) => {},
+            Some(other) => panic!("expected the loop's statistics, got {:?}", other),
+            None => panic!("timed out waiting for the loop's statistics"),
+        }
+    }
+
+    /// listen on a free loopback port, returning the listener's token and address
+    pub fn listen (&self) -> (Token, SocketAddr) {
+        let addr = free_addr();
+        (self.handle.listen(addr).unwrap(), addr)
+    }
+
+    /// connect a peer to a listener, returning the peer and the token the loop gave it
+    pub fn accept (&self, addr: SocketAddr) -> (Peer, Token) {
+        let peer = Peer::connect(addr);
+        let token = match self.wait_for("a connect request", |m| match *m {
+            OutputMessage::ConnectRequest { .. } => true,
+            _ => false,
+        }) {
+            OutputMessage::ConnectRequest { client, .. } => client,
+            _ => unreachable!(),
+        };
+
+        (peer, token)
+    }
+
+    /// wait for data from a connection until `expected` has all arrived
+    pub fn expect_data (&self, token: Token, expected: &[u8]) {
+        let mut received = Vec::new();
+
+        while received.len() < expected.len() {
+            match self.wait_for("data", |m| match *m {
+                OutputMessage::Data { token: t, .. } => t == token,
+                _ => false,
+            }) {
+                OutputMessage::Data { data, .. } => received.extend(data),
+                _ => unreachable!(),
+            }
+        }
+
+        assert_eq!(&received[..], expected);
+    }
+
+    /// shut the loop down and wait for its thread to finish
+    pub fn shutdown (&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.input.send(InputMessage::Shutdown);
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for TestLoop {
+    fn drop (&mut self) {
+        if !thread::panicking() {
+            self.shutdown();
+        }
+    }
+}
+
+/// wait for the next output message from a TestLoop and check it matches a pattern
+///
+/// With `=> expr`, the value of `expr` is returned, with the pattern's bindings in scope.
+#[macro_export]
+macro_rules! expect_output {
+    ($test:expr, $pattern:pat) => {
+        expect_output!($test, $pattern => ())
+    };
+    ($test:expr, $pattern:pat => $result:expr) => {
+        match $test.next() {
+            Some($pattern) => $result,
+            Some(other) => panic!("expected {}, got {:?}", stringify!($pattern), other),
+            None => panic!("timed out waiting for {}", stringify!($pattern)),
+        }
+    };
+}
+
+/// an address on loopback that nothing is listening on
+///
+/// The port is found by binding to it and letting it go, so something else could take it in the
+/// meantime, but that's unlikely in a test.
+pub fn free_addr () -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// a listener for the loop's outgoing connections to connect to
+pub struct PeerListener {
+    listener: TcpListener,
+}
+
+impl PeerListener {
+    pub fn bind () -> PeerListener {
+        PeerListener {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+        }
+    }
+
+    pub fn addr (&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    pub fn accept (&self) -> Peer {
+        Peer::new(self.listener.accept().unwrap().0)
+    }
+}
+
+/// one step of a Peer's script
+#[derive(Debug, Clone)]
+pub enum Step {
+    Send(Vec<u8>),
+
+    /// read until exactly this has arrived
+    Expect(Vec<u8>),
+
+    /// do nothing for a while, in milliseconds
+    Stall(u64),
+
+    /// shut down the write side of the connection
+    HalfClose,
+
+    /// read until the loop closes its side
+    ExpectEof,
+
+    /// abort the connection, so the loop sees a reset rather than a clean close
+    Reset,
+}
+
+/// the far end of a connection to or from the loop
+pub struct Peer {
+    stream: Option<TcpStream>,
+}
+
+impl Peer {
+    pub fn new (stream: TcpStream) -> Peer {
+        stream.set_read_timeout(Some(Duration::from_millis(TIMEOUT_MS))).unwrap();
+
+        Peer {
+            stream: Some(stream),
+        }
+    }
+
+    pub fn connect (addr: SocketAddr) -> Peer {
+        Peer::new(TcpStream::connect(addr).unwrap())
+    }
+
+    pub fn local_addr (&self) -> SocketAddr {
+        self.stream.as_ref().expect("the peer was reset").local_addr().unwrap()
+    }
+
+    pub fn send (&mut self, data: &[u8]) {
+        self.stream().write_all(data).unwrap();
+    }
+
+    pub fn expect (&mut self, expected: &[u8]) {
+        let mut received = vec![0; expected.len()];
+        self.stream().read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], expected);
+    }
+
+    pub fn stall (&mut self, ms: u64) {
+        thread::sleep(Duration::from_millis(ms));
+    }
+
+    pub fn half_close (&mut self) {
+        self.stream().shutdown(Shutdown::Write).unwrap();
+    }
+
+    pub fn expect_eof (&mut self) {
+        let mut buf = [0; 1024];
+
+        loop {
+            match self.stream().read(&mut buf) {
+                Ok(0) => return,
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => return,
+                Err(e) => panic!("expected the connection to close, got {:?}", e),
+            }
+        }
+    }
+
+    pub fn reset (&mut self) {
+        if let Some(stream) = self.stream.take() {
+            set_linger_zero(&stream);
+        }
+    }
+
+    pub fn step (&mut self, step: &Step) {
+        match *step {
+            Step::Send(ref data)   => self.send(data),
+            Step::Expect(ref data) => self.expect(data),
+            Step::Stall(ms)        => self.stall(ms),
+            Step::HalfClose        => self.half_close(),
+            Step::ExpectEof        => self.expect_eof(),
+            Step::Reset            => self.reset(),
+        }
+    }
+
+    /// play a script on a thread of its own; join the thread to find out whether it went to plan
+    pub fn play (mut self, script: Vec<Step>) -> thread::JoinHandle<Peer> {
+        thread::spawn(move || {
+            for step in script.iter() {
+                self.step(step);
+            }
+            self
+        })
+    }
+
+    fn stream (&mut self) -> &mut TcpStream {
+        match self.stream {
+            Some(ref mut stream) => stream,
+            None => panic!("the peer was reset"),
+        }
+    }
+}
+
+/// make closing the stream send a RST
+#[cfg(unix)]
+fn set_linger_zero (stream: &TcpStream) {
+    use libc;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+    };
+    assert!(result == 0, "failed to set SO_LINGER: {:?}", io::Error::last_os_error());
+}
+
+#[cfg(not(unix))]
+fn set_linger_zero (_stream: &TcpStream) {
+    // no way to ask for a reset here; the peer just closes
+}
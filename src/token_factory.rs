@@ -1,11 +1,24 @@
 use mio;
 use Token;
 
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+/// produces the tokens the loop assigns to accepted connections
+///
+/// Tokens with the top bit set are reserved for the loop's own use; the loop treats a token in
+/// that range as already in use.
 pub trait Factory: Sync + Send {
     fn produce (&mut self) -> Token;
+
+    /// hand back a token the loop is done with
+    ///
+    /// The loop calls this once it has sent the final Output::Close or Output::DirtyClose for a
+    /// token, whether the token came from this factory or from the downstream.  Factories that
+    /// recycle tokens should ignore any they didn't hand out.
+    fn release (&mut self, _token: Token) {}
 }
 
 #[derive(Clone)]
@@ -25,3 +38,152 @@ impl Factory for SequentialFactory {
         mio::Token(self.counter.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+struct FreeList {
+    range:  Range<usize>,
+    next:   usize,
+    free:   VecDeque<usize>,
+    issued: HashSet<usize>,
+}
+
+/// a factory that hands out released tokens again
+///
+/// Released tokens are reused oldest first, so a token stays unused for as long as possible
+/// before it comes back.  Clones share the same pool, so the downstream can keep a clone to
+/// produce the tokens of its own requests.
+///
+/// If every token in the range is in use, the start of the range is handed out again; the loop
+/// notices the collision and turns the connection away.
+#[derive(Clone)]
+pub struct RecyclingFactory {
+    state: Arc<Mutex<FreeList>>,
+}
+
+impl RecyclingFactory {
+    pub fn new () -> RecyclingFactory {
+        RecyclingFactory::with_range(1..usize::max_value() >> 1)
+    }
+
+    /// create a factory that only hands out tokens in `range`
+    pub fn with_range (range: Range<usize>) -> RecyclingFactory {
+        assert!(range.start < range.end, "empty token range {:?}", range);
+
+        RecyclingFactory {
+            state: Arc::new(Mutex::new(FreeList {
+                next:   range.start,
+                range:  range,
+                free:   VecDeque::new(),
+                issued: HashSet::new(),
+            })),
+        }
+    }
+
+    /// whether `token` falls in the range this factory hands out
+    pub fn contains (&self, token: Token) -> bool {
+        let state = self.state.lock().unwrap();
+        token.0 >= state.range.start && token.0 < state.range.end
+    }
+}
+impl Factory for RecyclingFactory {
+    fn produce (&mut self) -> Token {
+        let mut state = self.state.lock().unwrap();
+
+        let token = if let Some(token) = state.free.pop_front() {
+            token
+        } else if state.next < state.range.end {
+            state.next += 1;
+            state.next - 1
+        } else {
+            warn!("token range {:?} exhausted", state.range);
+            state.range.start
+        };
+
+        state.issued.insert(token);
+        mio::Token(token)
+    }
+
+    fn release (&mut self, token: Token) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.issued.remove(&token.0) {
+            state.free.push_back(token.0);
+        }
+    }
+}
+
+/// the kinds of token a NamespacedFactory keeps apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Namespace {
+    /// tokens for Input::ListenRequest
+    Listener,
+
+    /// tokens the loop assigns to accepted connections
+    Inbound,
+
+    /// tokens for Input::ConnectRequest and Input::ConnectHostRequest
+    Outbound,
+}
+
+/// a factory that reserves a separate range of tokens for each Namespace
+///
+/// The loop produces from the Inbound range; the downstream keeps a clone to produce listener and
+/// outbound tokens with `produce_in`.  Any token can be mapped back to its namespace, and each
+/// range recycles released tokens like a RecyclingFactory.
+#[derive(Clone)]
+pub struct NamespacedFactory {
+    listeners: RecyclingFactory,
+    inbound:   RecyclingFactory,
+    outbound:  RecyclingFactory,
+}
+
+impl NamespacedFactory {
+    pub fn new (listeners: Range<usize>, inbound: Range<usize>, outbound: Range<usize>) -> NamespacedFactory {
+        assert!(disjoint(&listeners, &inbound) && disjoint(&listeners, &outbound) && disjoint(&inbound, &outbound),
+            "overlapping token ranges {:?}, {:?} and {:?}", listeners, inbound, outbound);
+
+        NamespacedFactory {
+            listeners: RecyclingFactory::with_range(listeners),
+            inbound:   RecyclingFactory::with_range(inbound),
+            outbound:  RecyclingFactory::with_range(outbound),
+        }
+    }
+
+    /// produce a token in a particular namespace
+    pub fn produce_in (&mut self, namespace: Namespace) -> Token {
+        self.factory(namespace).produce()
+    }
+
+    /// which namespace a token belongs to, if any
+    pub fn namespace (&self, token: Token) -> Option<Namespace> {
+        [Namespace::Listener, Namespace::Inbound, Namespace::Outbound].iter()
+            .map(|x| *x)
+            .find(|namespace| match *namespace {
+                Namespace::Listener => self.listeners.contains(token),
+                Namespace::Inbound  => self.inbound.contains(token),
+                Namespace::Outbound => self.outbound.contains(token),
+            })
+    }
+
+    fn factory (&mut self, namespace: Namespace) -> &mut RecyclingFactory {
+        match namespace {
+            Namespace::Listener => &mut self.listeners,
+            Namespace::Inbound  => &mut self.inbound,
+            Namespace::Outbound => &mut self.outbound,
+        }
+    }
+}
+impl Factory for NamespacedFactory {
+    fn produce (&mut self) -> Token {
+        self.inbound.produce()
+    }
+
+    fn release (&mut self, token: Token) {
+        if let Some(namespace) = self.namespace(token) {
+            self.factory(namespace).release(token);
+        }
+    }
+}
+
+fn disjoint (a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.end <= b.start || b.end <= a.start
+}
@@ -0,0 +1,130 @@
+//! integration tests for Input::InjectFault
+//!
+//! Run with `cargo test --features "test-support fault-injection"`.
+
+#[macro_use]
+extern crate tcp_loop;
+
+use std::io;
+
+use tcp_loop::testing::TestLoop;
+use tcp_loop::{CloseReason, Fault, InputMessage, OutputMessage, Phase};
+
+fn inject (test: &TestLoop, token: tcp_loop::Token, fault: Fault) {
+    test.send(InputMessage::InjectFault { token: token, fault: fault });
+}
+
+#[test]
+fn capped_writes () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::CapWrites(Some(3)));
+    test.send(InputMessage::Data { token: client, data: b"split into pieces".to_vec() });
+    peer.expect(b"split into pieces");
+
+    // one write of at most three bytes per flush, each after the first waiting for the socket
+    // to be writable, where without the cap it would have gone out in one
+    let stats = test.handle.stats(client).unwrap();
+    assert_eq!(stats.bytes_written, 17);
+    assert_eq!(stats.writes, 6);
+}
+
+#[test]
+fn zero_write_cap () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    // taken as a cap of one byte, rather than never writing at all
+    inject(&test, client, Fault::CapWrites(Some(0)));
+    test.send(InputMessage::Data { token: client, data: b"slow".to_vec() });
+    peer.expect(b"slow");
+
+    let stats = test.handle.stats(client).unwrap();
+    assert_eq!(stats.writes, 4);
+}
+
+#[test]
+fn delayed_reads () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::DelayReads(300));
+    test.handle.stats(client).unwrap();
+
+    peer.send(b"later");
+    test.expect_quiet(150);
+    test.expect_data(client, b"later");
+}
+
+#[test]
+fn failed_read () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::FailRead(io::Error::new(io::ErrorKind::Other, "injected")));
+    peer.send(b"x");
+
+    expect_output!(test, OutputMessage::DirtyClose { token, reason, .. } => {
+        assert_eq!(token, client);
+        assert_eq!(reason.phase(), Some(Phase::Read));
+    });
+}
+
+#[test]
+fn failed_write () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (_peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::FailWrite(io::Error::new(io::ErrorKind::Other, "injected")));
+
+    expect_output!(test, OutputMessage::DirtyClose { token, reason, .. } => {
+        assert_eq!(token, client);
+        assert_eq!(reason.phase(), Some(Phase::Write));
+    });
+}
+
+#[test]
+fn reset () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::Reset);
+
+    expect_output!(test, OutputMessage::DirtyClose { token, reason, .. } => {
+        assert_eq!(token, client);
+        assert_eq!(reason.error().map(|e| e.kind()), Some(io::ErrorKind::ConnectionReset));
+    });
+    peer.expect_eof();
+}
+
+#[test]
+fn hangup () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::Hangup);
+
+    expect_output!(test, OutputMessage::Close { token, reason: CloseReason::Hangup, .. } => assert_eq!(token, client));
+    peer.expect_eof();
+}
+
+#[test]
+fn clear () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    inject(&test, client, Fault::FailRead(io::Error::new(io::ErrorKind::Other, "injected")));
+    inject(&test, client, Fault::Clear);
+
+    peer.send(b"fine");
+    test.expect_data(client, b"fine");
+}
@@ -0,0 +1,742 @@
+//! integration tests covering each Input message over loopback
+//!
+//! Run with `cargo test --features test-support`.
+
+extern crate mio;
+#[macro_use]
+extern crate tcp_loop;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tcp_loop::testing::{free_addr, Peer, PeerListener, Step, TestLoop};
+use tcp_loop::{AccessRules, CloseReason, ConnectOptions, ConnectionState, DownstreamPolicy};
+use tcp_loop::{InputMessage, ListenOptions, OutputMessage, Phase, RateLimit, RejectReason, UserContext};
+
+#[test]
+fn listen_request () {
+    let mut test = TestLoop::start();
+    let token = test.token();
+    let addr = free_addr();
+
+    test.send(InputMessage::ListenRequest {
+        listener: token,
+        addr:     addr,
+        options:  ListenOptions::default(),
+        context:  None,
+    });
+    expect_output!(test, OutputMessage::ListenResponse { listener, .. } => assert_eq!(listener, token));
+
+    let (_peer, _) = test.accept(addr);
+}
+
+#[test]
+fn listen_request_failure () {
+    let mut test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let token = test.token();
+
+    // the address is taken by the first listener
+    test.send(InputMessage::ListenRequest {
+        listener: token,
+        addr:     addr,
+        options:  ListenOptions::default(),
+        context:  None,
+    });
+    expect_output!(test, OutputMessage::DirtyClose { token: t, reason, .. } => {
+        assert_eq!(t, token);
+        assert_eq!(reason.phase(), Some(Phase::Register));
+    });
+}
+
+#[test]
+fn listen_request_collision () {
+    let test = TestLoop::start();
+    let (listener, _) = test.listen();
+
+    test.send(InputMessage::ListenRequest {
+        listener: listener,
+        addr:     free_addr(),
+        options:  ListenOptions::default(),
+        context:  None,
+    });
+    expect_output!(test, OutputMessage::TokenCollision { token, .. } => assert_eq!(token, listener));
+}
+
+#[test]
+fn connect_request () {
+    let mut test = TestLoop::start();
+    let server = PeerListener::bind();
+    let token = test.token();
+
+    test.send(InputMessage::ConnectRequest {
+        token:   token,
+        addr:    server.addr(),
+        options: ConnectOptions::default(),
+        context: None,
+    });
+
+    let mut peer = server.accept();
+    expect_output!(test, OutputMessage::ConnectResponse { token: t, addr, .. } => {
+        assert_eq!(t, token);
+        assert_eq!(addr, server.addr());
+    });
+
+    peer.send(b"hello");
+    test.expect_data(token, b"hello");
+}
+
+#[test]
+fn connect_request_refused () {
+    let mut test = TestLoop::start();
+    let token = test.token();
+
+    test.send(InputMessage::ConnectRequest {
+        token:   token,
+        addr:    free_addr(),
+        options: ConnectOptions::default(),
+        context: None,
+    });
+    expect_output!(test, OutputMessage::DirtyClose { token: t, reason, .. } => {
+        assert_eq!(t, token);
+        assert_eq!(reason.phase(), Some(Phase::Connect));
+    });
+}
+
+#[test]
+fn connect_host_request () {
+    let mut test = TestLoop::start();
+    let server = PeerListener::bind();
+    let token = test.token();
+
+    test.send(InputMessage::ConnectHostRequest {
+        token:   token,
+        host:    "localhost".to_string(),
+        port:    server.addr().port(),
+        options: ConnectOptions::default(),
+        context: None,
+    });
+
+    let _peer = server.accept();
+    expect_output!(test, OutputMessage::ConnectResponse { token: t, .. } => assert_eq!(t, token));
+}
+
+#[test]
+fn resolved_for_stale_request () {
+    let mut test = TestLoop::start();
+    let server = PeerListener::bind();
+    let token = test.token();
+
+    test.send(InputMessage::ConnectHostRequest {
+        token:   token,
+        host:    "localhost".to_string(),
+        port:    server.addr().port(),
+        options: ConnectOptions::default(),
+        context: None,
+    });
+
+    // an answer to some other request for the token, pointing nowhere, is ignored
+    test.send(InputMessage::Resolved {
+        token:   token,
+        request: u64::max_value(),
+        result:  Ok(vec![free_addr()]),
+    });
+
+    let _peer = server.accept();
+    expect_output!(test, OutputMessage::ConnectResponse { token: t, addr, .. } => {
+        assert_eq!(t, token);
+        assert_eq!(addr, server.addr());
+    });
+}
+
+#[test]
+fn accept () {
+    let mut test = TestLoop::start();
+    let token = test.token();
+    let mut options = ListenOptions::default();
+    options.manual_accept = true;
+    let addr = free_addr();
+
+    test.send(InputMessage::ListenRequest { listener: token, addr: addr, options: options, context: None });
+    expect_output!(test, OutputMessage::ListenResponse { .. });
+
+    let (mut peer, client) = test.accept(addr);
+    peer.send(b"early");
+    test.expect_quiet(200);
+
+    test.send(InputMessage::Accept { token: client });
+    test.expect_data(client, b"early");
+}
+
+#[test]
+fn reject () {
+    let mut test = TestLoop::start();
+    let token = test.token();
+    let mut options = ListenOptions::default();
+    options.manual_accept = true;
+    let addr = free_addr();
+
+    test.send(InputMessage::ListenRequest { listener: token, addr: addr, options: options, context: None });
+    expect_output!(test, OutputMessage::ListenResponse { .. });
+
+    let (mut peer, client) = test.accept(addr);
+    test.send(InputMessage::Reject { token: client, reason: "go away".to_string() });
+
+    expect_output!(test, OutputMessage::DirtyClose { token, reason, .. } => {
+        assert_eq!(token, client);
+        assert_eq!(reason.phase(), Some(Phase::Policy));
+    });
+    peer.expect_eof();
+}
+
+#[test]
+fn proxy_header_timeout () {
+    let mut test = TestLoop::start();
+    let token = test.token();
+    let mut options = ListenOptions::default();
+    options.proxy_protocol = true;
+    options.header_timeout_ms = 100;
+    let addr = free_addr();
+
+    test.send(InputMessage::ListenRequest { listener: token, addr: addr, options: options, context: None });
+    expect_output!(test, OutputMessage::ListenResponse { .. });
+
+    // half a header, and then nothing
+    let mut peer = Peer::connect(addr);
+    peer.send(b"PROXY TCP4 192.0.2.1 ");
+
+    expect_output!(test, OutputMessage::DirtyClose { reason, .. } => assert_eq!(reason.phase(), Some(Phase::Timeout)));
+    peer.expect_eof();
+}
+
+#[test]
+fn data () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    test.send(InputMessage::Data { token: client, data: b"ping".to_vec() });
+    peer.expect(b"ping");
+
+    peer.send(b"pong");
+    test.expect_data(client, b"pong");
+}
+
+#[test]
+fn data_vectored () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    let body: Arc<[u8]> = Arc::from(&b"body"[..]);
+    test.send(InputMessage::DataVectored {
+        token: client,
+        data:  vec![Arc::from(&b"head "[..]), Arc::from(&b""[..]), body.clone()],
+    });
+    peer.expect(b"head body");
+
+    // the pieces went out together, and the loop let go of its references once they had
+    let stats = test.handle.stats(client).unwrap();
+    assert_eq!(stats.writes, 1);
+    assert_eq!(Arc::strong_count(&body), 1);
+}
+
+#[test]
+fn broadcast () {
+    let mut test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut a, client_a) = test.accept(addr);
+    let (mut b, client_b) = test.accept(addr);
+    let stale = test.token();
+
+    test.send(InputMessage::Broadcast {
+        tokens: vec![client_a, stale, client_b, client_a],
+        data:   Arc::from(&b"news"[..]),
+    });
+    a.expect(b"news");
+    b.expect(b"news");
+
+    for &client in [client_a, client_b].iter() {
+        let stats = test.handle.stats(client).unwrap();
+        assert_eq!(stats.bytes_written, 4);
+    }
+}
+
+#[test]
+fn link () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut a, a_token) = test.accept(addr);
+    let (mut b, b_token) = test.accept(addr);
+
+    test.send(InputMessage::Link { a: a_token, b: b_token });
+    test.handle.stats(a_token).unwrap();  // the link is in place once this is answered
+
+    a.send(b"to b");
+    b.expect(b"to b");
+    b.send(b"to a");
+    a.expect(b"to a");
+
+    // closing one side closes the other, once what it sent last has been passed on
+    a.send(b"last words");
+    a.half_close();
+    drop(a);
+    b.expect(b"last words");
+    b.expect_eof();
+}
+
+#[test]
+fn link_close_drains () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut a, a_token) = test.accept(addr);
+    let (mut b, b_token) = test.accept(addr);
+
+    // b is written to slowly, so what a sends is still queued for it when a goes
+    let limit = RateLimit { bytes_per_second: 100_000, burst: 10_000 };
+    test.send(InputMessage::SetRateLimits { token: b_token, read: None, write: Some(limit) });
+    test.send(InputMessage::Link { a: a_token, b: b_token });
+    test.handle.stats(a_token).unwrap();
+
+    let sent: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+    a.send(&sent);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while test.handle.stats(a_token).unwrap().bytes_read < sent.len() as u64 {
+        assert!(Instant::now() < deadline, "the loop didn't read what a sent");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    test.send(InputMessage::Close { token: a_token, dirty: false });
+    b.expect(&sent);
+    b.expect_eof();
+
+    expect_output!(test, OutputMessage::Close { token, .. } => assert_eq!(token, a_token));
+    expect_output!(test, OutputMessage::Close { token, reason, .. } => {
+        assert_eq!(token, b_token);
+        match reason {
+            CloseReason::LinkClosed => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn statistics_request () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    peer.send(b"12345");
+    test.expect_data(client, b"12345");
+
+    test.send(InputMessage::StatisticsRequest { token: client });
+    expect_output!(test, OutputMessage::StatisticsResponse { token, stats, state, .. } => {
+        assert_eq!(token, client);
+        assert_eq!(stats.bytes_read, 5);
+        assert_eq!(state, ConnectionState::Established);
+    });
+}
+
+#[test]
+fn info_request () {
+    let test = TestLoop::start();
+    let (listener, addr) = test.listen();
+    let (peer, client) = test.accept(addr);
+
+    test.send(InputMessage::InfoRequest { token: client });
+    expect_output!(test, OutputMessage::InfoResponse { info, .. } => {
+        assert_eq!(info.peer, peer.local_addr());
+        assert_eq!(info.local, Some(addr));
+        assert_eq!(info.listener, Some(listener));
+    });
+}
+
+#[test]
+fn set_context () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    let context: UserContext = Arc::new(42u32);
+    test.send(InputMessage::SetContext { token: client, context: Some(context) });
+    test.handle.stats(client).unwrap();  // the context is attached once this is answered
+
+    peer.send(b"x");
+    expect_output!(test, OutputMessage::Data { context, .. } => {
+        assert_eq!(context.unwrap().downcast_ref::<u32>(), Some(&42));
+    });
+}
+
+#[test]
+fn set_access_rules () {
+    let test = TestLoop::start();
+    let (listener, addr) = test.listen();
+
+    test.send(InputMessage::SetAccessRules {
+        listener: listener,
+        rules:    AccessRules { allow: vec![], deny: vec!["127.0.0.0/8".parse().unwrap()] },
+    });
+    test.sync();
+
+    let mut peer = Peer::connect(addr);
+    expect_output!(test, OutputMessage::ConnectionRejected { listener: l, reason, .. } => {
+        assert_eq!(l, listener);
+        assert_eq!(reason, RejectReason::AccessDenied);
+    });
+    peer.expect_eof();
+}
+
+#[test]
+fn loop_statistics_request () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (_peer, _) = test.accept(addr);
+
+    test.send(InputMessage::LoopStatisticsRequest);
+    expect_output!(test, OutputMessage::LoopStatisticsResponse { stats } => {
+        assert_eq!(stats.connections_accepted, 1);
+    });
+}
+
+#[test]
+fn set_downstream () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    let (downstream, output) = mpsc::channel();
+    test.send(InputMessage::SetDownstream { downstream: downstream });
+    expect_output!(test, OutputMessage::Handoff { token: None });
+
+    peer.send(b"moved");
+    match output.recv_timeout(Duration::from_secs(5)).unwrap() {
+        OutputMessage::Data { token, data, .. } => {
+            assert_eq!(token, client);
+            assert_eq!(data, b"moved");
+        },
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn set_token_downstream () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut a, a_token) = test.accept(addr);
+    let (mut b, b_token) = test.accept(addr);
+
+    let (downstream, output) = mpsc::channel();
+    test.send(InputMessage::SetTokenDownstream { token: a_token, downstream: Some(downstream) });
+    expect_output!(test, OutputMessage::Handoff { token } => assert_eq!(token, Some(a_token)));
+
+    a.send(b"a");
+    match output.recv_timeout(Duration::from_secs(5)).unwrap() {
+        OutputMessage::Data { token, .. } => assert_eq!(token, a_token),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // other tokens are left alone
+    b.send(b"b");
+    test.expect_data(b_token, b"b");
+
+    // and going back to the main downstream hands off on the token's own
+    test.send(InputMessage::SetTokenDownstream { token: a_token, downstream: None });
+    match output.recv_timeout(Duration::from_secs(5)).unwrap() {
+        OutputMessage::Handoff { token } => assert_eq!(token, Some(a_token)),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn set_token_downstream_unknown_token () {
+    let mut test = TestLoop::start();
+    let server = PeerListener::bind();
+    let token = test.token();
+
+    let (downstream, output) = mpsc::channel();
+    test.send(InputMessage::SetTokenDownstream { token: token, downstream: Some(downstream) });
+    assert_eq!(output.recv_timeout(Duration::from_secs(5)).err(), Some(mpsc::RecvTimeoutError::Disconnected));
+
+    // so the connection that takes the token later reports to the main downstream
+    test.send(InputMessage::ConnectRequest {
+        token:   token,
+        addr:    server.addr(),
+        options: ConnectOptions::default(),
+        context: None,
+    });
+    let _peer = server.accept();
+    expect_output!(test, OutputMessage::ConnectResponse { token: t, .. } => assert_eq!(t, token));
+}
+
+#[test]
+fn set_fallback_downstream () {
+    let (fallback, output) = mpsc::channel();
+    let mut test = TestLoop::with_policy(DownstreamPolicy::Fallback);
+    let (_, addr) = test.listen();
+
+    test.send(InputMessage::SetFallbackDownstream { downstream: fallback });
+    test.sync();
+
+    // losing the main downstream moves output to the fallback
+    let main = std::mem::replace(&mut test.output, mpsc::channel().1);
+    drop(main);
+
+    let _peer = Peer::connect(addr);
+    match output.recv_timeout(Duration::from_secs(5)).unwrap() {
+        OutputMessage::ConnectRequest { .. } => {},
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn call () {
+    let test = TestLoop::start();
+    let (listener, _) = test.listen();
+    let server = PeerListener::bind();
+
+    let client = test.handle.connect(server.addr()).unwrap();
+    let _peer = server.accept();
+    assert_eq!(test.handle.stats(client).unwrap().bytes_read, 0);
+
+    // nothing to answer with for tokens the loop doesn't know
+    assert!(test.handle.stats(mio::Token(0)).is_err());
+
+    match test.handle.call(InputMessage::InfoRequest { token: listener }) {
+        Err(_) => {},
+        Ok(reply) => panic!("unexpected {:?}", reply),
+    }
+
+    // replies don't go to the downstream
+    test.expect_quiet(200);
+}
+
+#[test]
+fn call_timeout () {
+    let mut test = TestLoop::start();
+    let server = PeerListener::bind();
+
+    // a connect can't be answered before the call gives up straight away
+    test.handle.set_timeout(Some(Duration::from_millis(0)));
+    let err = test.handle.connect(server.addr()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    // the reply that comes too late goes to the downstream
+    let _peer = server.accept();
+    expect_output!(test, OutputMessage::ConnectResponse { .. });
+}
+
+#[test]
+fn close () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    // data queued before a clean close is still written
+    test.send(InputMessage::Data { token: client, data: b"bye".to_vec() });
+    test.send(InputMessage::Close { token: client, dirty: false });
+
+    expect_output!(test, OutputMessage::Close { token, reason, .. } => {
+        assert_eq!(token, client);
+        match reason {
+            CloseReason::Requested => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    });
+
+    peer.expect(b"bye");
+    peer.expect_eof();
+}
+
+#[test]
+fn drain_timeout () {
+    let mut test = TestLoop::start();
+    let token = test.token();
+    let mut options = ListenOptions::default();
+    options.drain_timeout_ms = 200;
+    let addr = free_addr();
+
+    test.send(InputMessage::ListenRequest { listener: token, addr: addr, options: options, context: None });
+    expect_output!(test, OutputMessage::ListenResponse { .. });
+
+    // far more than the socket buffers hold, to a peer that never reads
+    let (_peer, client) = test.accept(addr);
+    test.send(InputMessage::Data { token: client, data: vec![0; 16 * 1024 * 1024] });
+    test.send(InputMessage::Close { token: client, dirty: false });
+
+    expect_output!(test, OutputMessage::DirtyClose { token, reason, .. } => {
+        assert_eq!(token, client);
+        assert_eq!(reason.phase(), Some(Phase::Timeout));
+    });
+}
+
+#[test]
+fn close_listener () {
+    let test = TestLoop::start();
+    let (listener, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    test.send(InputMessage::Close { token: listener, dirty: false });
+    expect_output!(test, OutputMessage::Close { token, .. } => assert_eq!(token, listener));
+
+    assert!(std::net::TcpStream::connect(addr).is_err());
+
+    // clients it already accepted carry on
+    peer.send(b"still here");
+    test.expect_data(client, b"still here");
+}
+
+#[test]
+fn shutdown () {
+    let mut test = TestLoop::start();
+    let (listener, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    test.send(InputMessage::Shutdown);
+
+    let mut closed = vec![];
+    for _ in 0..2 {
+        expect_output!(test, OutputMessage::Close { token, reason: CloseReason::Shutdown, .. } => closed.push(token));
+    }
+    closed.sort();
+    let mut expected = vec![listener, client];
+    expected.sort();
+    assert_eq!(closed, expected);
+
+    peer.expect_eof();
+    test.shutdown();
+}
+
+#[test]
+fn peer_half_close () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (peer, client) = test.accept(addr);
+
+    let peer = peer.play(vec![Step::Send(b"last".to_vec()), Step::HalfClose, Step::ExpectEof]);
+    test.expect_data(client, b"last");
+
+    // only linked connections stay half open; anything else is closed once the peer stops
+    expect_output!(test, OutputMessage::Close { token, reason, .. } => {
+        assert_eq!(token, client);
+        match reason {
+            CloseReason::Hangup => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    });
+    peer.join().unwrap();
+}
+
+#[test]
+fn peer_reset () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (peer, client) = test.accept(addr);
+
+    peer.play(vec![Step::Stall(50), Step::Reset]).join().unwrap();
+
+    match test.wait_for("the close", |m| match *m {
+        OutputMessage::Close { .. } | OutputMessage::DirtyClose { .. } => true,
+        _ => false,
+    }) {
+        OutputMessage::DirtyClose { token, .. } => assert_eq!(token, client),
+        other => panic!("expected a dirty close, got {:?}", other),
+    }
+}
+
+#[test]
+fn set_rate_limits () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    let limit = RateLimit { bytes_per_second: 2_000, burst: 1_000 };
+    test.send(InputMessage::SetRateLimits { token: client, read: None, write: Some(limit) });
+
+    // a burst's worth goes straight out, and the rest at the limit
+    let start = Instant::now();
+    test.send(InputMessage::Data { token: client, data: vec![7; 3_000] });
+    peer.expect(&[7; 3_000][..]);
+    assert!(start.elapsed() >= Duration::from_millis(800));
+
+    let stats = test.handle.stats(client).unwrap();
+    assert_eq!(stats.bytes_written, 3_000);
+    assert!(stats.write_throttled_ms > 0);
+}
+
+#[test]
+fn rate_limit_small_burst () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    // a burst this small is raised to what the limit needs to be reached between timer ticks
+    let limit = RateLimit { bytes_per_second: 1_000_000, burst: 1 };
+    test.send(InputMessage::SetRateLimits { token: client, read: None, write: Some(limit) });
+
+    let start = Instant::now();
+    test.send(InputMessage::Data { token: client, data: vec![7; 500_000] });
+    peer.expect(&vec![7; 500_000]);
+
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(300), "sent too fast: {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "sent too slowly: {:?}", elapsed);
+}
+
+#[test]
+fn set_priority () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut peer, client) = test.accept(addr);
+
+    test.send(InputMessage::SetPriority { token: client, weight: 2 });
+
+    // a turn is 16 KiB per unit of weight, so a big send arrives over several turns
+    let sent: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    peer.send(&sent);
+
+    let mut received = Vec::new();
+    while received.len() < sent.len() {
+        let (token, data) = expect_output!(test, OutputMessage::Data { token, data, .. } => (token, data));
+        assert_eq!(token, client);
+        assert!(data.len() <= 32 * 1024);
+        received.extend(data);
+    }
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn priority_under_load () {
+    let test = TestLoop::start();
+    let (_, addr) = test.listen();
+    let (mut bulk_peer, bulk) = test.accept(addr);
+    let (mut urgent_peer, urgent) = test.accept(addr);
+
+    test.send(InputMessage::SetPriority { token: urgent, weight: 8 });
+
+    // the bulk peer reads everything as fast as it can, counting as it goes
+    const CHUNK: usize = 64 * 1024;
+    const TOTAL: usize = 64 * 1024 * 1024;
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    let reader = thread::spawn(move || {
+        let chunk = vec![0; CHUNK];
+        for _ in 0..TOTAL / CHUNK {
+            bulk_peer.expect(&chunk);
+            counter.fetch_add(CHUNK, Ordering::SeqCst);
+        }
+    });
+
+    // one huge write is spread over many turns, so the small one behind it isn't kept waiting
+    test.send(InputMessage::Data { token: bulk, data: vec![0; TOTAL] });
+    test.send(InputMessage::Data { token: urgent, data: b"ping".to_vec() });
+
+    urgent_peer.expect(b"ping");
+    let before_ping = received.load(Ordering::SeqCst);
+    assert!(before_ping < TOTAL / 2, "{} bytes of the bulk transfer went first", before_ping);
+
+    reader.join().unwrap();
+}